@@ -1,25 +1,35 @@
 use super::{Chip, CodeSegment, RomSegment};
 use crate::{
-    image::{BootHeaderCfg, PartitionCfg},
+    efuse::{EfuseLayout, BL602_EFUSE},
+    format::UF2_FAMILY_BL602,
+    image::{BootHeader, BootHeaderCfgFile, PartitionCfg, BOOT_HEADER_LEN, IMG_LEN_OFFSET},
     Error,
 };
+use byteorder::{ByteOrder, LittleEndian};
 use deku::prelude::*;
 
-pub const DEFAULT_PARTITION_CFG: &'static [u8] = include_bytes!("cfg/partition_cfg_2M.toml");
-pub const DEFAULT_BOOTHEADER_CFG: &'static [u8] = include_bytes!("cfg/efuse_bootheader_cfg.conf");
-pub const RO_PARAMS: &'static [u8] = include_bytes!("cfg/ro_params.dtb");
-pub const BLSP_BOOT2: &'static [u8] = include_bytes!("image/blsp_boot2.bin");
-pub const EFLASH_LOADER: &'static [u8] = include_bytes!("image/eflash_loader_40m.bin");
+pub const DEFAULT_PARTITION_CFG: &[u8] = include_bytes!("cfg/partition_cfg_2M.toml");
+pub const DEFAULT_BOOTHEADER_CFG: &[u8] = include_bytes!("cfg/efuse_bootheader_cfg.conf");
+pub const RO_PARAMS: &[u8] = include_bytes!("cfg/ro_params.dtb");
+pub const BLSP_BOOT2: &[u8] = include_bytes!("image/blsp_boot2.bin");
+pub const EFLASH_LOADER: &[u8] = include_bytes!("image/eflash_loader_40m.bin");
 const ROM_START: u32 = 0x23000000;
 // 16MB
 const ROM_END: u32 = 0x23000000 + 0x1000000;
+// ITCM/DTCM and WRAM, through the instruction and data buses
+const RAM_RANGES: [(u32, u32); 2] = [(0x22008000, 0x22050000), (0x42008000, 0x42050000)];
 
 #[derive(Copy, Clone, Debug)]
 pub struct Bl602;
 
 impl Bl602 {
     fn addr_is_flash(&self, addr: u32) -> bool {
-        addr >= ROM_START && addr < ROM_END
+        (ROM_START..ROM_END).contains(&addr)
+    }
+    fn addr_is_ram(&self, addr: u32) -> bool {
+        RAM_RANGES
+            .iter()
+            .any(|&(start, end)| (start..end).contains(&addr))
     }
 }
 
@@ -28,8 +38,49 @@ impl Chip for Bl602 {
         "riscv32imac-unknown-none-elf"
     }
 
-    fn get_eflash_loader(&self) -> &[u8] {
-        EFLASH_LOADER
+    fn get_eflash_loader(&self) -> Option<&'static [u8]> {
+        Some(EFLASH_LOADER)
+    }
+
+    fn get_default_partition_cfg(&self) -> &'static [u8] {
+        DEFAULT_PARTITION_CFG
+    }
+
+    fn get_default_boot_header_cfg(&self) -> &'static [u8] {
+        DEFAULT_BOOTHEADER_CFG
+    }
+
+    fn get_ro_params(&self) -> &'static [u8] {
+        RO_PARAMS
+    }
+
+    fn get_boot2(&self) -> Option<&'static [u8]> {
+        Some(BLSP_BOOT2)
+    }
+
+    fn parse_boot_header_cfg(&self, cfg: &[u8]) -> Result<Box<dyn BootHeader>, Error> {
+        let BootHeaderCfgFile { boot_header_cfg } = toml::from_slice(cfg)?;
+        Ok(Box::new(boot_header_cfg))
+    }
+
+    fn boot_header_len(&self) -> usize {
+        BOOT_HEADER_LEN
+    }
+
+    fn segment_count(&self, boot_header: &[u8]) -> u32 {
+        LittleEndian::read_u32(&boot_header[IMG_LEN_OFFSET..IMG_LEN_OFFSET + 4])
+    }
+
+    fn efuse_layout(&self) -> Option<&'static EfuseLayout> {
+        Some(&BL602_EFUSE)
+    }
+
+    fn flash_window_size(&self) -> u32 {
+        ROM_END - ROM_START
+    }
+
+    fn uf2_family_id(&self) -> Option<u32> {
+        Some(UF2_FAMILY_BL602)
     }
 
     fn get_flash_segment<'a>(&self, code_segment: CodeSegment<'a>) -> Option<RomSegment<'a>> {
@@ -43,17 +94,29 @@ impl Chip for Bl602 {
         }
     }
 
+    fn get_ram_segment<'a>(&self, code_segment: CodeSegment<'a>) -> Option<RomSegment<'a>> {
+        if self.addr_is_ram(code_segment.addr) {
+            Some(RomSegment::from_code_segment(
+                code_segment.addr,
+                code_segment,
+            ))
+        } else {
+            None
+        }
+    }
+
     fn with_boot2(
         &self,
         mut partition_cfg: PartitionCfg,
-        mut bootheader_cfg: BootHeaderCfg,
+        mut bootheader_cfg: Box<dyn BootHeader>,
         ro_params: Vec<u8>,
+        boot2: &[u8],
         bin: &[u8],
-    ) -> Result<Vec<RomSegment>, Error> {
+    ) -> Result<Vec<RomSegment<'_>>, Error> {
         partition_cfg.update()?;
         let partition_cfg = partition_cfg.to_bytes()?;
 
-        let boot2image = bootheader_cfg.make_image(0x2000, Vec::from(BLSP_BOOT2))?;
+        let boot2image = bootheader_cfg.make_image(0x2000, Vec::from(boot2))?;
         let fw_image = bootheader_cfg.make_image(0x1000, Vec::from(bin))?;
 
         let segments = vec![
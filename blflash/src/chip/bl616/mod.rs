@@ -1,25 +1,34 @@
 use super::{Chip, CodeSegment, RomSegment};
 use crate::{
-    image::{BootHeaderCfg, PartitionCfg},
+    image::{
+        Bl616BootHeaderCfgFile, BootHeader, PartitionCfg, BL616_BOOT_HEADER_LEN,
+        BL616_IMG_LEN_OFFSET,
+    },
     Error,
 };
+use byteorder::{ByteOrder, LittleEndian};
 use deku::prelude::*;
 
-pub const DEFAULT_PARTITION_CFG: &'static [u8] = include_bytes!("cfg/partition_cfg_2M.toml");
-pub const DEFAULT_BOOTHEADER_CFG: &'static [u8] = include_bytes!("cfg/efuse_bootheader_cfg.conf");
-pub const RO_PARAMS: &'static [u8] = include_bytes!("cfg/ro_params.dtb");
-pub const BLSP_BOOT2: &'static [u8] = include_bytes!("image/blsp_boot2.bin");
-pub const EFLASH_LOADER: &'static [u8] = include_bytes!("image/eflash_loader_40m.bin");
-const ROM_START: u32 = 0x23000000;
-// 16MB
-const ROM_END: u32 = 0x23000000 + 0x1000000;
+pub const DEFAULT_PARTITION_CFG: &[u8] = include_bytes!("cfg/partition_cfg_2M.toml");
+pub const DEFAULT_BOOTHEADER_CFG: &[u8] = include_bytes!("cfg/efuse_bootheader_cfg.conf");
+pub const RO_PARAMS: &[u8] = include_bytes!("cfg/ro_params.dtb");
+// flash XIP window
+const ROM_START: u32 = 0xA0000000;
+// 64MB
+const ROM_END: u32 = 0xA0000000 + 0x4000000;
+// OCRAM followed by WRAM
+const RAM_START: u32 = 0x62FC0000;
+const RAM_END: u32 = 0x63038000;
 
 #[derive(Copy, Clone, Debug)]
 pub struct Bl616;
 
 impl Bl616 {
     fn addr_is_flash(&self, addr: u32) -> bool {
-        addr >= ROM_START && addr < ROM_END
+        (ROM_START..ROM_END).contains(&addr)
+    }
+    fn addr_is_ram(&self, addr: u32) -> bool {
+        (RAM_START..RAM_END).contains(&addr)
     }
 }
 
@@ -28,8 +37,43 @@ impl Chip for Bl616 {
         "riscv32imac-unknown-none-elf"
     }
 
-    fn get_eflash_loader(&self) -> &[u8] {
-        EFLASH_LOADER
+    // no BL616 eflash_loader or boot2 is bundled, the Bouffalo SDK builds with the
+    // 256-byte BL616 boot header are passed with --eflash-loader and --boot2
+    fn get_eflash_loader(&self) -> Option<&'static [u8]> {
+        None
+    }
+
+    fn get_default_partition_cfg(&self) -> &'static [u8] {
+        DEFAULT_PARTITION_CFG
+    }
+
+    fn get_default_boot_header_cfg(&self) -> &'static [u8] {
+        DEFAULT_BOOTHEADER_CFG
+    }
+
+    fn get_ro_params(&self) -> &'static [u8] {
+        RO_PARAMS
+    }
+
+    fn get_boot2(&self) -> Option<&'static [u8]> {
+        None
+    }
+
+    fn parse_boot_header_cfg(&self, cfg: &[u8]) -> Result<Box<dyn BootHeader>, Error> {
+        let Bl616BootHeaderCfgFile { boot_header_cfg } = toml::from_slice(cfg)?;
+        Ok(Box::new(boot_header_cfg))
+    }
+
+    fn boot_header_len(&self) -> usize {
+        BL616_BOOT_HEADER_LEN
+    }
+
+    fn segment_count(&self, boot_header: &[u8]) -> u32 {
+        LittleEndian::read_u32(&boot_header[BL616_IMG_LEN_OFFSET..BL616_IMG_LEN_OFFSET + 4])
+    }
+
+    fn flash_window_size(&self) -> u32 {
+        ROM_END - ROM_START
     }
 
     fn get_flash_segment<'a>(&self, code_segment: CodeSegment<'a>) -> Option<RomSegment<'a>> {
@@ -43,17 +87,29 @@ impl Chip for Bl616 {
         }
     }
 
+    fn get_ram_segment<'a>(&self, code_segment: CodeSegment<'a>) -> Option<RomSegment<'a>> {
+        if self.addr_is_ram(code_segment.addr) {
+            Some(RomSegment::from_code_segment(
+                code_segment.addr,
+                code_segment,
+            ))
+        } else {
+            None
+        }
+    }
+
     fn with_boot2(
         &self,
         mut partition_cfg: PartitionCfg,
-        mut bootheader_cfg: BootHeaderCfg,
+        mut bootheader_cfg: Box<dyn BootHeader>,
         ro_params: Vec<u8>,
+        boot2: &[u8],
         bin: &[u8],
-    ) -> Result<Vec<RomSegment>, Error> {
+    ) -> Result<Vec<RomSegment<'_>>, Error> {
         partition_cfg.update()?;
         let partition_cfg = partition_cfg.to_bytes()?;
 
-        let boot2image = bootheader_cfg.make_image(0x2000, Vec::from(BLSP_BOOT2))?;
+        let boot2image = bootheader_cfg.make_image(0x2000, Vec::from(boot2))?;
         let fw_image = bootheader_cfg.make_image(0x1000, Vec::from(bin))?;
 
         let segments = vec![
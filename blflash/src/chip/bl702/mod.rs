@@ -0,0 +1,123 @@
+use super::{Chip, CodeSegment, RomSegment};
+use crate::{
+    image::{BootHeader, BootHeaderCfgFile, PartitionCfg, BOOT_HEADER_LEN, IMG_LEN_OFFSET},
+    Error,
+};
+use byteorder::{ByteOrder, LittleEndian};
+use deku::prelude::*;
+
+pub const DEFAULT_PARTITION_CFG: &[u8] = include_bytes!("cfg/partition_cfg_2M.toml");
+pub const DEFAULT_BOOTHEADER_CFG: &[u8] = include_bytes!("cfg/efuse_bootheader_cfg.conf");
+// the RF parameters are a BL602 thing, the BL70x keep theirs in efuse
+pub const RO_PARAMS: &[u8] = &[];
+const ROM_START: u32 = 0x23000000;
+// 8MB
+const ROM_END: u32 = 0x23000000 + 0x800000;
+// ITCM/DTCM and OCRAM, through the instruction and data buses
+const RAM_RANGES: [(u32, u32); 2] = [(0x22014000, 0x22030000), (0x42014000, 0x42030000)];
+
+/// BL702, BL704 and BL706, which differ only in package
+#[derive(Copy, Clone, Debug)]
+pub struct Bl702;
+
+impl Bl702 {
+    fn addr_is_flash(&self, addr: u32) -> bool {
+        (ROM_START..ROM_END).contains(&addr)
+    }
+    fn addr_is_ram(&self, addr: u32) -> bool {
+        RAM_RANGES
+            .iter()
+            .any(|&(start, end)| (start..end).contains(&addr))
+    }
+}
+
+impl Chip for Bl702 {
+    fn target(&self) -> &'static str {
+        "riscv32imac-unknown-none-elf"
+    }
+
+    // no BL702 eflash_loader or boot2 is bundled, the Bouffalo SDK builds are
+    // passed with --eflash-loader and --boot2
+    fn get_eflash_loader(&self) -> Option<&'static [u8]> {
+        None
+    }
+
+    fn get_default_partition_cfg(&self) -> &'static [u8] {
+        DEFAULT_PARTITION_CFG
+    }
+
+    fn get_default_boot_header_cfg(&self) -> &'static [u8] {
+        DEFAULT_BOOTHEADER_CFG
+    }
+
+    fn get_ro_params(&self) -> &'static [u8] {
+        RO_PARAMS
+    }
+
+    fn get_boot2(&self) -> Option<&'static [u8]> {
+        None
+    }
+
+    fn parse_boot_header_cfg(&self, cfg: &[u8]) -> Result<Box<dyn BootHeader>, Error> {
+        let BootHeaderCfgFile { boot_header_cfg } = toml::from_slice(cfg)?;
+        Ok(Box::new(boot_header_cfg))
+    }
+
+    fn boot_header_len(&self) -> usize {
+        BOOT_HEADER_LEN
+    }
+
+    fn segment_count(&self, boot_header: &[u8]) -> u32 {
+        LittleEndian::read_u32(&boot_header[IMG_LEN_OFFSET..IMG_LEN_OFFSET + 4])
+    }
+
+    fn flash_window_size(&self) -> u32 {
+        ROM_END - ROM_START
+    }
+
+    fn get_flash_segment<'a>(&self, code_segment: CodeSegment<'a>) -> Option<RomSegment<'a>> {
+        if self.addr_is_flash(code_segment.addr) {
+            Some(RomSegment::from_code_segment(
+                code_segment.addr - ROM_START,
+                code_segment,
+            ))
+        } else {
+            None
+        }
+    }
+
+    fn get_ram_segment<'a>(&self, code_segment: CodeSegment<'a>) -> Option<RomSegment<'a>> {
+        if self.addr_is_ram(code_segment.addr) {
+            Some(RomSegment::from_code_segment(
+                code_segment.addr,
+                code_segment,
+            ))
+        } else {
+            None
+        }
+    }
+
+    fn with_boot2(
+        &self,
+        mut partition_cfg: PartitionCfg,
+        mut bootheader_cfg: Box<dyn BootHeader>,
+        _ro_params: Vec<u8>,
+        boot2: &[u8],
+        bin: &[u8],
+    ) -> Result<Vec<RomSegment<'_>>, Error> {
+        partition_cfg.update()?;
+        let partition_cfg = partition_cfg.to_bytes()?;
+
+        let boot2image = bootheader_cfg.make_image(0x2000, Vec::from(boot2))?;
+        let fw_image = bootheader_cfg.make_image(0x1000, Vec::from(bin))?;
+
+        let segments = vec![
+            RomSegment::from_vec(0x0, boot2image),
+            RomSegment::from_vec(0xe000, partition_cfg.clone()),
+            RomSegment::from_vec(0xf000, partition_cfg),
+            RomSegment::from_vec(0x10000, fw_image),
+        ];
+
+        Ok(segments)
+    }
+}
@@ -0,0 +1,190 @@
+use super::{Chip, CodeSegment, RomSegment};
+use crate::{
+    image::{
+        Bl808BootHeaderCfgFile, BootHeader, PartitionCfg, BL808_BOOT_HEADER_LEN, BL808_CORES,
+        BL808_IMG_LEN_OFFSET,
+    },
+    Error,
+};
+use byteorder::{ByteOrder, LittleEndian};
+use deku::prelude::*;
+
+pub const DEFAULT_PARTITION_CFG: &[u8] = include_bytes!("cfg/partition_cfg_16M.toml");
+pub const DEFAULT_BOOTHEADER_CFG: &[u8] = include_bytes!("cfg/efuse_bootheader_cfg.conf");
+// the RF parameters live in efuse, as on BL70x
+pub const RO_PARAMS: &[u8] = &[];
+// flash XIP window, each core maps it at its image
+const ROM_START: u32 = 0x58000000;
+// 64MB
+const ROM_END: u32 = 0x58000000 + 0x4000000;
+// M0 OCRAM followed by WRAM
+const RAM_START: u32 = 0x22020000;
+const RAM_END: u32 = 0x22058000;
+/// Partition each core's image goes to
+pub const CORE_PARTITIONS: [(&str, &str); 3] = [("m0", "FW"), ("d0", "D0FW"), ("lp", "LPFW")];
+/// Boot groups as (header address, cores), the first core given is the group's master
+const GROUPS: [(u32, &[&str]); 2] = [(0x0, &["m0", "lp"]), (0x1000, &["d0"])];
+
+/// BL808 with its M0, D0 and LP cores
+#[derive(Copy, Clone, Debug)]
+pub struct Bl808;
+
+impl Bl808 {
+    fn addr_is_flash(&self, addr: u32) -> bool {
+        (ROM_START..ROM_END).contains(&addr)
+    }
+    fn addr_is_ram(&self, addr: u32) -> bool {
+        (RAM_START..RAM_END).contains(&addr)
+    }
+}
+
+impl Chip for Bl808 {
+    fn target(&self) -> &'static str {
+        "riscv32imac-unknown-none-elf"
+    }
+
+    // no BL808 eflash_loader or boot2 is bundled, the Bouffalo SDK's M0 builds with
+    // the 352-byte BL808 boot header are passed with --eflash-loader and --boot2
+    fn get_eflash_loader(&self) -> Option<&'static [u8]> {
+        None
+    }
+
+    fn get_default_partition_cfg(&self) -> &'static [u8] {
+        DEFAULT_PARTITION_CFG
+    }
+
+    fn get_default_boot_header_cfg(&self) -> &'static [u8] {
+        DEFAULT_BOOTHEADER_CFG
+    }
+
+    fn get_ro_params(&self) -> &'static [u8] {
+        RO_PARAMS
+    }
+
+    fn get_boot2(&self) -> Option<&'static [u8]> {
+        None
+    }
+
+    fn parse_boot_header_cfg(&self, cfg: &[u8]) -> Result<Box<dyn BootHeader>, Error> {
+        let Bl808BootHeaderCfgFile { boot_header_cfg } = toml::from_slice(cfg)?;
+        Ok(Box::new(boot_header_cfg))
+    }
+
+    fn boot_header_len(&self) -> usize {
+        BL808_BOOT_HEADER_LEN
+    }
+
+    fn segment_count(&self, boot_header: &[u8]) -> u32 {
+        LittleEndian::read_u32(&boot_header[BL808_IMG_LEN_OFFSET..BL808_IMG_LEN_OFFSET + 4])
+    }
+
+    fn flash_window_size(&self) -> u32 {
+        ROM_END - ROM_START
+    }
+
+    fn get_flash_segment<'a>(&self, code_segment: CodeSegment<'a>) -> Option<RomSegment<'a>> {
+        if self.addr_is_flash(code_segment.addr) {
+            Some(RomSegment::from_code_segment(
+                code_segment.addr - ROM_START,
+                code_segment,
+            ))
+        } else {
+            None
+        }
+    }
+
+    fn get_ram_segment<'a>(&self, code_segment: CodeSegment<'a>) -> Option<RomSegment<'a>> {
+        if self.addr_is_ram(code_segment.addr) {
+            Some(RomSegment::from_code_segment(
+                code_segment.addr,
+                code_segment,
+            ))
+        } else {
+            None
+        }
+    }
+
+    fn with_boot2(
+        &self,
+        mut partition_cfg: PartitionCfg,
+        mut bootheader_cfg: Box<dyn BootHeader>,
+        _ro_params: Vec<u8>,
+        boot2: &[u8],
+        bin: &[u8],
+    ) -> Result<Vec<RomSegment<'_>>, Error> {
+        partition_cfg.update()?;
+        let partition_cfg = partition_cfg.to_bytes()?;
+
+        let boot2image = bootheader_cfg.make_image(0x2000, Vec::from(boot2))?;
+        let fw_image = bootheader_cfg.make_image(0x1000, Vec::from(bin))?;
+
+        let segments = vec![
+            RomSegment::from_vec(0x0, boot2image),
+            RomSegment::from_vec(0xe000, partition_cfg.clone()),
+            RomSegment::from_vec(0xf000, partition_cfg),
+            RomSegment::from_vec(0x10000, fw_image),
+        ];
+
+        Ok(segments)
+    }
+
+    fn cores(&self) -> &'static [&'static str] {
+        &BL808_CORES
+    }
+
+    fn with_cores(
+        &self,
+        mut partition_cfg: PartitionCfg,
+        bootheader_cfg: &[u8],
+        images: Vec<(&str, Vec<u8>)>,
+    ) -> Result<Vec<RomSegment<'static>>, Error> {
+        let Bl808BootHeaderCfgFile {
+            boot_header_cfg: mut header,
+        } = toml::from_slice(bootheader_cfg)?;
+
+        let mut placed = Vec::new();
+        for (core, mut image) in images {
+            let &(_, name) = CORE_PARTITIONS
+                .iter()
+                .find(|(c, _)| *c == core)
+                .ok_or_else(|| Error::UnsupportedCore(core.to_string()))?;
+            let entry = partition_cfg
+                .pt_entry
+                .iter()
+                .find(|entry| entry.name == name)
+                .ok_or_else(|| Error::MissingPartition(name.to_string()))?;
+            image.resize(image.len().div_ceil(16) * 16, 0xff);
+            if image.len() as u32 > entry.size0 {
+                return Err(Error::PartitionOverflow {
+                    name: name.to_string(),
+                    len: image.len() as u32,
+                    size: entry.size0,
+                });
+            }
+            placed.push((core, entry.address0, image));
+        }
+
+        let mut segments = Vec::new();
+        for &(header_addr, cores) in GROUPS.iter() {
+            let group: Vec<_> = cores
+                .iter()
+                .filter_map(|core| placed.iter().find(|(c, _, _)| c == core))
+                .map(|(core, addr, image)| (*core, addr - header_addr, &image[..]))
+                .collect();
+            if !group.is_empty() {
+                let group_header = header.make_group_header(&group)?;
+                segments.push(RomSegment::from_vec(header_addr, group_header));
+            }
+        }
+
+        partition_cfg.update()?;
+        let partition_cfg = partition_cfg.to_bytes()?;
+        segments.push(RomSegment::from_vec(0xe000, partition_cfg.clone()));
+        segments.push(RomSegment::from_vec(0xf000, partition_cfg));
+        for (_, addr, image) in placed {
+            segments.push(RomSegment::from_vec(addr, image));
+        }
+
+        Ok(segments)
+    }
+}
@@ -1,29 +1,78 @@
 pub mod bl602;
 pub mod bl616;
+pub mod bl702;
+pub mod bl808;
+use crate::efuse::EfuseLayout;
 pub use crate::elf::{CodeSegment, FirmwareImage, RomSegment};
-use crate::image::{BootHeaderCfg, PartitionCfg};
+use crate::image::{BootHeader, PartitionCfg};
 use crate::Error;
 pub use bl602::Bl602;
 pub use bl616::Bl616;
+pub use bl702::Bl702;
+pub use bl808::Bl808;
 use std::str::FromStr;
 
 pub trait Chip {
     fn target(&self) -> &'static str;
-    fn get_eflash_loader(&self) -> &[u8];
+    /// The bundled eflash_loader, `None` for chips we don't ship one for
+    fn get_eflash_loader(&self) -> Option<&'static [u8]>;
+    /// partition_cfg.toml used when none is given
+    fn get_default_partition_cfg(&self) -> &'static [u8];
+    /// efuse_bootheader_cfg.conf used when none is given
+    fn get_default_boot_header_cfg(&self) -> &'static [u8];
+    /// ro_params.dtb used when none is given
+    fn get_ro_params(&self) -> &'static [u8];
+    /// The bundled blsp_boot2, `None` for chips we don't ship one for
+    fn get_boot2(&self) -> Option<&'static [u8]>;
+    /// The chip's boot header, from an efuse_bootheader_cfg.conf
+    fn parse_boot_header_cfg(&self, cfg: &[u8]) -> Result<Box<dyn BootHeader>, Error>;
+    /// Length of the boot header the boot ROM expects in front of a RAM image
+    fn boot_header_len(&self) -> usize;
+    /// Segment count of a RAM image with `boot_header`
+    fn segment_count(&self, boot_header: &[u8]) -> u32;
+    /// eFuse map to decode the known fields with, `None` where we don't have one
+    fn efuse_layout(&self) -> Option<&'static EfuseLayout> {
+        None
+    }
+    /// Size of the flash XIP window, the most flash the chip can address
+    fn flash_window_size(&self) -> u32;
     fn get_flash_segment<'a>(&self, code_segment: CodeSegment<'a>) -> Option<RomSegment<'a>>;
+    /// The segment at its RAM address, if the boot ROM can load it there
+    fn get_ram_segment<'a>(&self, code_segment: CodeSegment<'a>) -> Option<RomSegment<'a>>;
     fn with_boot2(
         &self,
         partition_cfg: PartitionCfg,
-        bootheader_cfg: BootHeaderCfg,
+        bootheader_cfg: Box<dyn BootHeader>,
         ro_params: Vec<u8>,
+        boot2: &[u8],
         bin: &[u8],
-    ) -> Result<Vec<RomSegment>, Error>;
+    ) -> Result<Vec<RomSegment<'_>>, Error>;
+    /// Family ID marking UF2 blocks for this chip, if one is registered
+    fn uf2_family_id(&self) -> Option<u32> {
+        None
+    }
+    /// Cores that take an image of their own, none on single core chips
+    fn cores(&self) -> &'static [&'static str] {
+        &[]
+    }
+    /// Boot headers and partition table, with each (core, image) placed in the core's partition
+    fn with_cores(
+        &self,
+        _partition_cfg: PartitionCfg,
+        _bootheader_cfg: &[u8],
+        images: Vec<(&str, Vec<u8>)>,
+    ) -> Result<Vec<RomSegment<'static>>, Error> {
+        let core = images.first().map(|(core, _)| *core).unwrap_or_default();
+        Err(Error::UnsupportedCore(core.to_string()))
+    }
 }
 
 #[derive(Clone, Debug)]
 pub enum ChipType {
     BL602(Bl602),
     BL616(Bl616),
+    BL702(Bl702),
+    BL808(Bl808),
 }
 
 // Implement parsing for ChipName
@@ -34,18 +83,58 @@ impl FromStr for ChipType {
         match s.to_uppercase().as_str() {
             "BL602" => Ok(ChipType::BL602(Bl602)),
             "BL616" => Ok(ChipType::BL616(Bl616)),
-            _ => Ok(ChipType::BL602(Bl602)),
-            // use bl602 as default, no errors
-            // _ => Err(Error),
+            "BL702" | "BL704" | "BL706" => Ok(ChipType::BL702(Bl702)),
+            "BL808" => Ok(ChipType::BL808(Bl808)),
+            _ => Err(Error::UnknownChip(s.to_string())),
+        }
+    }
+}
+
+/// A chip name, or `auto` to identify the chip from its boot info
+#[derive(Clone, Debug)]
+pub enum ChipSelect {
+    Auto,
+    Chip(ChipType),
+}
+
+impl FromStr for ChipSelect {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.eq_ignore_ascii_case("auto") {
+            Ok(ChipSelect::Auto)
+        } else {
+            s.parse().map(ChipSelect::Chip)
+        }
+    }
+}
+
+impl ChipSelect {
+    /// The named chip, `None` for auto
+    pub fn chip(&self) -> Option<ChipType> {
+        match self {
+            ChipSelect::Auto => None,
+            ChipSelect::Chip(chip) => Some(chip.clone()),
         }
     }
 }
 
 impl ChipType {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ChipType::BL602(_) => "BL602",
+            ChipType::BL616(_) => "BL616",
+            ChipType::BL702(_) => "BL702",
+            ChipType::BL808(_) => "BL808",
+        }
+    }
+
     pub fn to_box(self) -> Box<dyn Chip> {
         match self {
             ChipType::BL602(inst) => Box::new(inst),
             ChipType::BL616(inst) => Box::new(inst),
+            ChipType::BL702(inst) => Box::new(inst),
+            ChipType::BL808(inst) => Box::new(inst),
         }
     }
 }
@@ -1,14 +1,13 @@
 #![macro_use]
 
-use crate::{Error, RomError};
+use crate::{reset::ResetConfig, trace::TraceRecorder, transport::Transport, Error, RomError};
 use byteorder::{ByteOrder, LittleEndian, ReadBytesExt, WriteBytesExt};
 use deku::prelude::*;
 use std::convert::TryFrom;
 use std::io::{Cursor, Read, Write};
-use std::thread::sleep;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
-use serial::{BaudRate, SerialPort, SerialPortSettings};
+use serial::BaudRate;
 
 pub const DEFAULT_BAUDRATE: BaudRate = BaudRate::Baud115200;
 
@@ -18,6 +17,7 @@ macro_rules! impl_command(
             type Response = $r;
 
             const CMD_ID: u8 = $id;
+            const NAME: &'static str = stringify!($t);
         }
         impl Response for $r {}
     );
@@ -26,6 +26,7 @@ macro_rules! impl_command(
             type Response = crate::connection::NoResponsePayload;
 
             const CMD_ID: u8 = $id;
+            const NAME: &'static str = stringify!($t);
         }
     );
 );
@@ -54,86 +55,76 @@ pub trait Response: for<'a> DekuContainerRead<'a> + Sized {
 pub trait Command: DekuContainerWrite {
     type Response: Response;
     const CMD_ID: u8;
-    fn checksum(&self) -> u8 {
-        0
-    }
+    const NAME: &'static str;
+}
+
+/// Low byte of the sum over the little-endian length field and the payload
+pub fn checksum(payload: &[u8]) -> u8 {
+    (payload.len() as u16)
+        .to_le_bytes()
+        .iter()
+        .chain(payload)
+        .fold(0u8, |sum, b| sum.wrapping_add(*b))
 }
 
 pub struct Connection {
-    serial: Box<dyn SerialPort>,
+    transport: Box<dyn Transport>,
     baud_rate: Option<BaudRate>,
-    reset_pin: String,
-    boot_pin: String,
+    reset: ResetConfig,
+    trace: Option<TraceRecorder>,
+    // bytes read during the current command, kept for the trace
+    captured: Vec<u8>,
+    fatal_checksum_errors: bool,
 }
 
 impl Connection {
-    pub fn new(serial: impl SerialPort + 'static, reset_pin: String, boot_pin: String) -> Self {
+    pub fn new(transport: impl Transport + 'static, reset: ResetConfig) -> Self {
         Connection {
-            serial: Box::new(serial),
+            transport: Box::new(transport),
             baud_rate: None,
-            reset_pin,
-            boot_pin,
+            reset,
+            trace: None,
+            captured: Vec::new(),
+            fatal_checksum_errors: false,
         }
     }
 
-    pub fn into_inner(self) -> Box<dyn SerialPort> {
-        self.serial
-    }
-
-    fn set_pin(&mut self, pin: String, level: bool) -> Result<(), Error> {
-        let level = if pin.starts_with('!') { !level } else { level };
-        match pin.trim_start_matches('!') {
-            "rts" => {
-                self.serial.set_rts(level)?;
-            }
-            "dtr" => {
-                self.serial.set_dtr(level)?;
-            }
-            "null" => {
-                // do nothing
-            }
-            _ => return Err(Error::ArgsError),
-        }
-
-        sleep(Duration::from_millis(10));
-        Ok(())
+    pub fn set_trace(&mut self, trace: TraceRecorder) {
+        self.trace = Some(trace);
     }
 
-    fn set_reset_pin(&mut self, level: bool) -> Result<(), Error> {
-        self.set_pin(self.reset_pin.clone(), level)
+    /// Fail on a command checksum the chip rejects, instead of sending the command again
+    pub fn set_fatal_checksum_errors(&mut self, fatal: bool) {
+        self.fatal_checksum_errors = fatal;
     }
 
-    fn set_boot_pin(&mut self, level: bool) -> Result<(), Error> {
-        self.set_pin(self.boot_pin.clone(), level)
+    pub fn into_inner(self) -> Box<dyn Transport> {
+        self.transport
     }
 
     pub fn reset(&mut self) -> Result<(), Error> {
-        self.set_boot_pin(false)?;
-        self.set_reset_pin(true)?;
-        self.set_reset_pin(false)?;
-
-        Ok(())
+        self.reset.run(&self.reset.to_run, &mut *self.transport)
     }
 
     pub fn reset_to_flash(&mut self) -> Result<(), Error> {
-        self.set_boot_pin(true)?;
-        self.set_reset_pin(true)?;
-        self.set_reset_pin(false)?;
-        self.set_boot_pin(false)?;
+        self.reset.run(&self.reset.to_flash, &mut *self.transport)
+    }
 
-        Ok(())
+    pub fn timeout(&self) -> Duration {
+        self.transport.timeout()
     }
 
     pub fn set_timeout(&mut self, timeout: Duration) -> Result<(), Error> {
-        self.serial.set_timeout(timeout)?;
-        Ok(())
+        self.transport.set_timeout(timeout)
     }
 
     pub fn set_baud(&mut self, speed: BaudRate) -> Result<(), Error> {
         self.baud_rate = Some(speed);
-        self.serial
-            .reconfigure(&|setup: &mut dyn SerialPortSettings| setup.set_baud_rate(speed))?;
-        Ok(())
+        self.transport.set_baud(speed)
+    }
+
+    pub fn can_set_baud(&self) -> bool {
+        self.transport.can_set_baud()
     }
 
     pub fn with_timeout<T, F: FnMut(&mut Connection) -> Result<T, Error>>(
@@ -141,16 +132,32 @@ impl Connection {
         timeout: Duration,
         mut f: F,
     ) -> Result<T, Error> {
-        let old_timeout = self.serial.timeout();
-        self.serial.set_timeout(timeout)?;
+        let old_timeout = self.transport.timeout();
+        self.transport.set_timeout(timeout)?;
         let result = f(self);
-        self.serial.set_timeout(old_timeout)?;
+        self.transport.set_timeout(old_timeout)?;
         result
     }
 
+    /// Discard whatever is left in the receive buffer
+    pub fn drain(&mut self) -> Result<(), Error> {
+        self.with_timeout(Duration::from_millis(50), |connection| {
+            let mut buf = [0u8; 64];
+            while let Ok(size) = connection.transport.read(&mut buf) {
+                if size == 0 {
+                    break;
+                }
+            }
+            Ok(())
+        })
+    }
+
     fn read_exact(&mut self, len: usize) -> Result<Vec<u8>, Error> {
         let mut buf = vec![0u8; len];
-        self.serial.read_exact(&mut buf)?;
+        self.transport.read_exact(&mut buf)?;
+        if self.trace.is_some() {
+            self.captured.extend(&buf);
+        }
         Ok(buf)
     }
 
@@ -165,14 +172,19 @@ impl Connection {
                     Ok(vec![])
                 }
             }
+            // PD, the loader is still busy
+            [0x50, 0x44] => Err(Error::RomError(RomError::Polling)),
             // FL
             [0x46, 0x4c] => {
                 let code = self.read_exact(2)?;
                 let mut reader = Cursor::new(code);
                 let code = reader.read_u16::<LittleEndian>()?;
-                Err(Error::RomError(
-                    RomError::try_from(code).unwrap_or(RomError::Unknow),
-                ))
+                match RomError::try_from(code).unwrap_or(RomError::Unknow) {
+                    RomError::CmdCrcError if self.fatal_checksum_errors => {
+                        Err(Error::ChecksumRejected)
+                    }
+                    code => Err(Error::RomError(code)),
+                }
             }
             e => {
                 log::trace!("read_response err: {:x?}", e);
@@ -187,23 +199,138 @@ impl Connection {
     }
 
     pub fn write_all(&mut self, buf: &[u8]) -> Result<(), Error> {
-        Ok(self.serial.write_all(buf)?)
+        Ok(self.transport.write_all(buf)?)
     }
 
     pub fn flush(&mut self) -> Result<(), Error> {
-        Ok(self.serial.flush()?)
+        Ok(self.transport.flush()?)
     }
 
     pub fn command<C: Command>(&mut self, command: C) -> Result<C::Response, Error> {
+        self.send(command)?;
+        self.receive::<C>()
+    }
+
+    /// Send a long-running command and wait through pending replies for up to
+    /// `deadline`, calling `on_pending` with the time waited so far
+    pub fn command_with_deadline<C: Command>(
+        &mut self,
+        command: C,
+        deadline: Duration,
+        mut on_pending: impl FnMut(Duration),
+    ) -> Result<C::Response, Error> {
+        self.send(command)?;
+        self.receive_until::<C>(deadline, &mut on_pending)
+    }
+
+    /// Send `commands` keeping up to `depth` of them in flight, and hand each
+    /// response to `f` in order. Stops at the first error, with the receive
+    /// buffer drained of the responses still in flight.
+    pub fn pipeline<C: Command>(
+        &mut self,
+        commands: impl IntoIterator<Item = C>,
+        depth: usize,
+        mut f: impl FnMut(C::Response) -> Result<(), Error>,
+    ) -> Result<(), Error> {
+        let mut commands = commands.into_iter();
+        let mut in_flight = 0;
+        let result = loop {
+            let mut sent = Ok(());
+            while in_flight < depth {
+                match commands.next() {
+                    Some(command) => {
+                        sent = self.send(command);
+                        if sent.is_err() {
+                            break;
+                        }
+                        in_flight += 1;
+                    }
+                    None => break,
+                }
+            }
+            if let Err(e) = sent {
+                break Err(e);
+            }
+            if in_flight == 0 {
+                break Ok(());
+            }
+            in_flight -= 1;
+            if let Err(e) = self.receive::<C>().and_then(&mut f) {
+                break Err(e);
+            }
+        };
+        if result.is_err() && in_flight > 0 {
+            self.drain()?;
+        }
+        result
+    }
+
+    /// Write a request without waiting for its response
+    pub fn send<C: Command>(&mut self, command: C) -> Result<(), Error> {
         let req = self.to_cmd(command)?;
-        self.write_all(&req)?;
-        self.flush()?;
+        if let Some(trace) = &mut self.trace {
+            trace.request(C::NAME, &req)?;
+        }
+        let result = self.write_all(&req).and_then(|_| self.flush());
+        if result.is_err() {
+            if let Some(trace) = &mut self.trace {
+                trace.response(C::NAME, &[])?;
+            }
+        }
+        result
+    }
 
+    /// Read the response to the oldest request in flight
+    pub fn receive<C: Command>(&mut self) -> Result<C::Response, Error> {
+        let deadline = self.transport.timeout();
+        self.receive_until::<C>(deadline, &mut |_| {})
+    }
+
+    fn receive_until<C: Command>(
+        &mut self,
+        deadline: Duration,
+        on_pending: &mut dyn FnMut(Duration),
+    ) -> Result<C::Response, Error> {
+        self.captured.clear();
+        let result = self.read_command_response::<C>(deadline, on_pending);
+        if let Some(trace) = &mut self.trace {
+            trace.response(C::NAME, &self.captured)?;
+        }
+        result
+    }
+
+    /// Read a response, skipping the pending ones sent until `deadline` is over
+    fn read_final_response(
+        &mut self,
+        len: usize,
+        deadline: Duration,
+        on_pending: &mut dyn FnMut(Duration),
+    ) -> Result<Vec<u8>, Error> {
+        let start = Instant::now();
+        loop {
+            match self.read_response(len) {
+                Err(Error::RomError(RomError::Polling)) => {
+                    let waited = start.elapsed();
+                    if waited > deadline {
+                        return Err(Error::Timeout);
+                    }
+                    on_pending(waited);
+                }
+                result => return result,
+            }
+        }
+    }
+
+    fn read_command_response<C: Command>(
+        &mut self,
+        deadline: Duration,
+        on_pending: &mut dyn FnMut(Duration),
+    ) -> Result<C::Response, Error> {
         Ok(if let Some(resp) = C::Response::no_response_payload() {
-            self.read_response(0)?;
+            self.read_final_response(0, deadline, on_pending)?;
             resp
         } else {
-            let len = LittleEndian::read_u16(&self.read_response(2)?);
+            let len = LittleEndian::read_u16(&self.read_final_response(2, deadline, on_pending)?);
             let buf = Vec::new();
             let mut writer = Cursor::new(buf);
             writer.write_u16::<LittleEndian>(len)?;
@@ -219,7 +346,7 @@ impl Connection {
         let len = body.len() as u16;
 
         writer.write_u8(C::CMD_ID)?;
-        writer.write_u8(command.checksum())?;
+        writer.write_u8(checksum(&body))?;
         writer.write_u16::<LittleEndian>(len)?;
         writer.write_all(&body)?;
 
@@ -0,0 +1,125 @@
+//! Serial port discovery through Linux sysfs.
+//!
+//! Every `class/tty` entry with a USB device behind it is matched against the
+//! USB-UART bridges boards commonly use. The sysfs root is a parameter so a
+//! fake tree can stand in for `/sys`.
+
+use crate::Error;
+use std::{
+    fs,
+    io::{self, BufRead, Write},
+    path::{Path, PathBuf},
+};
+
+pub const SYSFS_ROOT: &str = "/sys";
+pub const DEV_ROOT: &str = "/dev";
+
+/// Known bridges as (vendor id, product id or any, name)
+pub const KNOWN_BRIDGES: &[(u16, Option<u16>, &str)] = &[
+    (0x1a86, Some(0x7523), "CH340"),
+    (0x1a86, Some(0x5523), "CH341"),
+    (0x1a86, Some(0x55d4), "CH9102"),
+    (0x10c4, Some(0xea60), "CP210x"),
+    (0x0403, Some(0x6001), "FT232R"),
+    (0x0403, Some(0x6010), "FT2232"),
+    (0x0403, Some(0x6014), "FT232H"),
+    (0x0403, Some(0x6015), "FT231X"),
+    (0x349b, None, "Bouffalo Lab"),
+    // what the Bouffalo SDK's CDC-ACM example enumerates as, BL616 and BL702 alike
+    (0xffff, Some(0xffff), "Bouffalo CDC"),
+];
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SerialDevice {
+    pub path: PathBuf,
+    pub vid: u16,
+    pub pid: u16,
+    pub bridge: &'static str,
+}
+
+fn read_id(dir: &Path, name: &str) -> Option<u16> {
+    let id = fs::read_to_string(dir.join(name)).ok()?;
+    u16::from_str_radix(id.trim(), 16).ok()
+}
+
+/// Vendor and product id of the USB device the tty hangs off
+fn usb_ids(tty: &Path) -> Option<(u16, u16)> {
+    let device = fs::canonicalize(tty.join("device")).ok()?;
+    // the ids live on the USB device, a level or two above the interface
+    device
+        .ancestors()
+        .take(4)
+        .find_map(|dir| Some((read_id(dir, "idVendor")?, read_id(dir, "idProduct")?)))
+}
+
+fn bridge_name(vid: u16, pid: u16) -> Option<&'static str> {
+    KNOWN_BRIDGES
+        .iter()
+        .find(|&&(v, p, _)| v == vid && p.map_or(true, |p| p == pid))
+        .map(|&(_, _, name)| name)
+}
+
+/// Serial devices behind known USB-UART bridges, sorted by path
+pub fn detect(sysfs: &Path, dev: &Path) -> Result<Vec<SerialDevice>, Error> {
+    let mut devices = Vec::new();
+    for entry in fs::read_dir(sysfs.join("class/tty"))? {
+        let entry = entry?;
+        let (vid, pid) = match usb_ids(&entry.path()) {
+            Some(ids) => ids,
+            None => continue,
+        };
+        if let Some(bridge) = bridge_name(vid, pid) {
+            devices.push(SerialDevice {
+                path: dev.join(entry.file_name()),
+                vid,
+                pid,
+                bridge,
+            });
+        }
+    }
+    devices.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(devices)
+}
+
+/// The only detected device, or the one picked from a numbered list on `input`
+pub fn choose(
+    devices: &[SerialDevice],
+    mut input: impl BufRead,
+    mut output: impl Write,
+) -> Result<PathBuf, Error> {
+    match devices {
+        [] => Err(Error::NoSerialPort),
+        [device] => Ok(device.path.clone()),
+        _ => {
+            for (i, device) in devices.iter().enumerate() {
+                writeln!(
+                    output,
+                    "{}: {} {} ({:04x}:{:04x})",
+                    i + 1,
+                    device.path.display(),
+                    device.bridge,
+                    device.vid,
+                    device.pid
+                )?;
+            }
+            write!(output, "Select a port [1-{}]: ", devices.len())?;
+            output.flush()?;
+            let mut line = String::new();
+            input.read_line(&mut line)?;
+            line.trim()
+                .parse::<usize>()
+                .ok()
+                .and_then(|i| devices.get(i.checked_sub(1)?))
+                .map(|device| device.path.clone())
+                .ok_or(Error::ArgsError)
+        }
+    }
+}
+
+/// Detect the port on this machine, asking on the terminal if there are several
+pub fn detect_port() -> Result<PathBuf, Error> {
+    let devices = detect(Path::new(SYSFS_ROOT), Path::new(DEV_ROOT))?;
+    let port = choose(&devices, io::stdin().lock(), io::stderr())?;
+    log::info!("Using serial port {}", port.display());
+    Ok(port)
+}
@@ -0,0 +1,119 @@
+//! eFuse layout and decoding of the fields we know about.
+
+use byteorder::{ByteOrder, LittleEndian};
+
+/// A bit field inside one 32-bit eFuse word
+pub struct EfuseField {
+    pub name: &'static str,
+    pub offset: u32,
+    pub pos: u32,
+    pub len: u32,
+}
+
+pub struct EfuseLayout {
+    pub size: u32,
+    pub fields: &'static [EfuseField],
+    /// Offset of the MAC address low word, the high word follows it
+    pub mac: u32,
+    /// Offsets of the 16-byte key slots
+    pub key_slots: &'static [u32],
+}
+
+const fn field(name: &'static str, offset: u32, pos: u32, len: u32) -> EfuseField {
+    EfuseField {
+        name,
+        offset,
+        pos,
+        len,
+    }
+}
+
+pub const BL602_EFUSE: EfuseLayout = EfuseLayout {
+    size: 128,
+    fields: &[
+        field("ef_sf_aes_mode", 0x00, 0, 2),
+        field("ef_sboot_sign_mode", 0x00, 2, 2),
+        field("ef_sboot_en", 0x00, 4, 2),
+        field("ef_cpu1_enc_en", 0x00, 6, 1),
+        field("ef_cpu0_enc_en", 0x00, 7, 1),
+        field("ef_uart_dis", 0x00, 8, 4),
+        field("ef_sdu_dis", 0x00, 14, 1),
+        field("ef_ble_dis", 0x00, 15, 1),
+        field("ef_wifi_dis", 0x00, 16, 1),
+        field("ef_0_key_enc_en", 0x00, 17, 1),
+        field("ef_dbg_jtag_1_dis", 0x00, 24, 2),
+        field("ef_dbg_jtag_dis", 0x00, 26, 2),
+        field("ef_dbg_mode", 0x00, 28, 4),
+        field("wr_lock_dbg_pwd", 0x7c, 14, 1),
+        field("wr_lock_wifi_mac", 0x7c, 15, 1),
+        field("wr_lock_key_slot_0", 0x7c, 16, 1),
+        field("wr_lock_key_slot_1", 0x7c, 17, 1),
+        field("wr_lock_key_slot_2", 0x7c, 18, 1),
+        field("wr_lock_key_slot_3", 0x7c, 19, 1),
+        field("rd_lock_dbg_pwd", 0x7c, 26, 1),
+        field("rd_lock_key_slot_0", 0x7c, 27, 1),
+        field("rd_lock_key_slot_1", 0x7c, 28, 1),
+        field("rd_lock_key_slot_2", 0x7c, 29, 1),
+        field("rd_lock_key_slot_3", 0x7c, 30, 1),
+    ],
+    mac: 0x14,
+    key_slots: &[0x1c, 0x2c, 0x3c, 0x4c, 0x60],
+};
+
+/// The part of `efuse` at absolute `offset`, if it was read
+fn get(efuse: &[u8], base: u32, offset: u32, len: usize) -> Option<&[u8]> {
+    let start = offset.checked_sub(base)? as usize;
+    efuse.get(start..start + len)
+}
+
+impl EfuseField {
+    /// Value of the field in `efuse`, which was read starting at `base`
+    pub fn value(&self, efuse: &[u8], base: u32) -> Option<u32> {
+        let word = LittleEndian::read_u32(get(efuse, base, self.offset, 4)?);
+        Some((word >> self.pos) & (u32::MAX >> (32 - self.len)))
+    }
+}
+
+impl EfuseLayout {
+    pub fn field(&self, name: &str) -> Option<&EfuseField> {
+        self.fields.iter().find(|field| field.name == name)
+    }
+
+    pub fn mac_address(&self, efuse: &[u8], base: u32) -> Option<[u8; 6]> {
+        let mac = get(efuse, base, self.mac, 6)?;
+        let mut addr = [0u8; 6];
+        addr.copy_from_slice(mac);
+        Some(addr)
+    }
+
+    /// Lines describing every known field in `efuse`, which was read starting at `base`
+    pub fn decode(&self, efuse: &[u8], base: u32) -> Vec<String> {
+        let mut lines = Vec::new();
+        if let Some(mac) = self.mac_address(efuse, base) {
+            lines.push(format!(
+                "{:<20} {}",
+                "mac_address",
+                mac.iter()
+                    .map(|b| format!("{:02x}", b))
+                    .collect::<Vec<_>>()
+                    .join(":")
+            ));
+        }
+        for field in self.fields {
+            if let Some(value) = field.value(efuse, base) {
+                lines.push(format!("{:<20} {:#x}", field.name, value));
+            }
+        }
+        for (i, &offset) in self.key_slots.iter().enumerate() {
+            if let Some(key) = get(efuse, base, offset, 16) {
+                let state = if key.iter().all(|&b| b == 0) {
+                    "empty"
+                } else {
+                    "programmed"
+                };
+                lines.push(format!("{:<20} {}", format!("key_slot_{}", i), state));
+            }
+        }
+        lines
+    }
+}
@@ -5,6 +5,7 @@ use xmas_elf::program::{SegmentData, Type};
 use xmas_elf::ElfFile;
 
 use crate::chip::Chip;
+use crate::Error;
 
 pub struct FirmwareImage<'a> {
     pub entry: u32,
@@ -27,7 +28,7 @@ impl<'a> FirmwareImage<'a> {
         self.elf.header.pt2.entry_point() as u32
     }
 
-    pub fn segments(&'a self) -> impl Iterator<Item = CodeSegment<'a>> + 'a {
+    pub fn segments(&self) -> impl Iterator<Item = CodeSegment<'a>> + '_ {
         self.elf
             .program_iter()
             .filter(|header| {
@@ -43,25 +44,71 @@ impl<'a> FirmwareImage<'a> {
                 Some(CodeSegment { addr, data, size })
             })
     }
-    pub fn to_flash_bin(&self, chip: &Box<dyn Chip>) -> Vec<u8> {
+    /// One segment per contiguous flash region, sorted by address, with gaps left out
+    pub fn to_flash_segments(&self, chip: &dyn Chip) -> Vec<RomSegment<'a>> {
+        merge_segments(
+            self.segments()
+                .filter_map(|segment| chip.get_flash_segment(segment))
+                .collect(),
+        )
+    }
+    /// The flash regions in one bin from offset 0, with the gaps filled with 0xFF
+    pub fn to_flash_bin(&self, chip: &dyn Chip) -> Vec<u8> {
+        segments_to_bin(&self.to_flash_segments(chip))
+    }
+    /// Segments to load into RAM through the boot ROM, failing if any of them isn't in RAM
+    pub fn to_ram_segments(&self, chip: &dyn Chip) -> Result<Vec<RomSegment<'a>>, Error> {
         let segs = self
             .segments()
-            .filter_map(|segment| chip.get_flash_segment(segment))
-            .collect::<Vec<_>>();
-        let size = segs
-            .iter()
-            .fold(0, |len, i| len.max(i.addr + i.data.len() as u32));
-
-        let mut bin = Vec::new();
-        bin.resize(size as usize, 0xFF);
-        for s in segs {
-            bin[s.addr as usize..s.addr as usize + s.data.len()].copy_from_slice(&s.data);
+            .map(|segment| {
+                chip.get_ram_segment(segment)
+                    .ok_or(Error::ElfNotRamLoadable)
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        if segs.is_empty() {
+            return Err(Error::ElfNotRamLoadable);
+        }
+        Ok(segs)
+    }
+}
+
+/// Sorts the segments by address and joins the adjacent or overlapping ones, later data wins
+pub fn merge_segments(mut segs: Vec<RomSegment<'_>>) -> Vec<RomSegment<'_>> {
+    segs.sort_by_key(|segment| segment.addr);
+
+    let mut regions: Vec<RomSegment> = Vec::new();
+    for seg in segs {
+        match regions.last_mut() {
+            Some(last) if seg.addr <= last.addr + last.size() => {
+                let start = (seg.addr - last.addr) as usize;
+                let end = start + seg.data.len();
+                let data = last.data.to_mut();
+                if data.len() < end {
+                    data.resize(end, 0xFF);
+                }
+                data[start..end].copy_from_slice(&seg.data);
+            }
+            _ => regions.push(seg),
         }
-        bin
     }
+    regions
 }
 
-#[derive(Debug, Ord, Eq)]
+/// The segments in one bin from offset 0, with the gaps filled with 0xFF
+pub fn segments_to_bin(segs: &[RomSegment]) -> Vec<u8> {
+    let size = segs
+        .iter()
+        .fold(0, |len, i| len.max(i.addr + i.data.len() as u32));
+
+    let mut bin = Vec::new();
+    bin.resize(size as usize, 0xFF);
+    for s in segs {
+        bin[s.addr as usize..s.addr as usize + s.data.len()].copy_from_slice(&s.data);
+    }
+    bin
+}
+
+#[derive(Debug, Eq)]
 /// A segment of code from the source elf
 pub struct CodeSegment<'a> {
     pub addr: u32,
@@ -74,7 +121,7 @@ impl<'a> CodeSegment<'a> {
         let data = data.as_ref();
         CodeSegment {
             addr,
-            data: &data,
+            data,
             size: data.len() as u32,
         }
     }
@@ -94,7 +141,13 @@ impl PartialEq for CodeSegment<'_> {
 
 impl PartialOrd for CodeSegment<'_> {
     fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-        self.addr.partial_cmp(&other.addr)
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for CodeSegment<'_> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.addr.cmp(&other.addr)
     }
 }
 
@@ -0,0 +1,588 @@
+//! In-process emulation of a BL602/BL616/BL702/BL808 for running the flasher without hardware.
+//!
+//! [`Emulator`] implements [`SerialPort`] and answers both the boot ROM and the
+//! eflash_loader commands from an in-memory flash array.
+
+use crate::{
+    chip::ChipType,
+    connection::checksum,
+    framing::{Framer, Incoming},
+    reset::Line,
+    RomError,
+};
+use byteorder::{ByteOrder, LittleEndian};
+use serial::{BaudRate, PortSettings, SerialPort, SerialPortSettings};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::{HashMap, VecDeque},
+    io::{self, Read, Write},
+    sync::{Arc, Mutex, MutexGuard},
+    time::Duration,
+};
+use xz2::stream::{Action, Status, Stream};
+
+const SECTOR_SIZE: usize = 4096;
+const EFUSE_SIZE: usize = 128;
+const BOOTROM_VERSION: u32 = 1;
+const LOAD_SEGMENT_HEADER_LEN: usize = 16;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Mode {
+    BootRom,
+    EflashLoader,
+}
+
+/// Misbehaviour to inject into the reply to a command
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Fault {
+    /// Run the command but reply with a corrupted header
+    Garbage,
+    /// Swallow the request without replying
+    Drop,
+    /// Fall back to the boot ROM without replying, as if the chip reset
+    Reset,
+    /// Flip a bit of the request on its way, like a noisy adapter
+    Corrupt,
+}
+
+/// An xz stream being written through the decompress-and-write command
+struct Decompress {
+    base: u32,
+    received: u32,
+    written: usize,
+    decoder: Stream,
+}
+
+enum Reply {
+    Ok,
+    Payload(Vec<u8>),
+    Fail(RomError),
+}
+
+struct Device {
+    chip: ChipType,
+    mode: Mode,
+    flash: Vec<u8>,
+    jedec_id: [u8; 3],
+    efuse: Vec<u8>,
+    memory: HashMap<u32, u32>,
+    decompress: Option<Decompress>,
+    ram: Vec<u8>,
+    segment_addr: u32,
+    segment_len: usize,
+    segments_left: u32,
+    boot_header_loaded: bool,
+    framer: Framer,
+    output: VecDeque<u8>,
+    strict_checksum: bool,
+    max_baud: Option<usize>,
+    pending: HashMap<u8, usize>,
+    lines: Vec<(Line, bool)>,
+    faults: Vec<(u8, Fault)>,
+    settings: PortSettings,
+    timeout: Duration,
+}
+
+/// A fake device speaking the ISP protocol. Clones share the same device.
+#[derive(Clone)]
+pub struct Emulator {
+    device: Arc<Mutex<Device>>,
+}
+
+impl Emulator {
+    pub fn new(chip: ChipType, flash_size: usize) -> Self {
+        Self::with_flash(chip, vec![0xff; flash_size])
+    }
+
+    pub fn with_flash(chip: ChipType, flash: Vec<u8>) -> Self {
+        // a Winbond part of the emulated size
+        let jedec_id = [0xef, 0x40, flash.len().trailing_zeros() as u8];
+        Emulator {
+            device: Arc::new(Mutex::new(Device {
+                chip,
+                mode: Mode::BootRom,
+                flash,
+                jedec_id,
+                efuse: vec![0; EFUSE_SIZE],
+                memory: HashMap::new(),
+                decompress: None,
+                ram: Vec::new(),
+                segment_addr: 0,
+                segment_len: 0,
+                segments_left: 0,
+                boot_header_loaded: false,
+                framer: Framer::default(),
+                output: VecDeque::new(),
+                strict_checksum: false,
+                max_baud: None,
+                pending: HashMap::new(),
+                lines: Vec::new(),
+                faults: Vec::new(),
+                settings: PortSettings {
+                    baud_rate: BaudRate::Baud115200,
+                    char_size: serial::Bits8,
+                    parity: serial::ParityNone,
+                    stop_bits: serial::Stop1,
+                    flow_control: serial::FlowNone,
+                },
+                timeout: Duration::from_millis(100),
+            })),
+        }
+    }
+
+    fn device(&self) -> MutexGuard<'_, Device> {
+        self.device.lock().unwrap()
+    }
+
+    /// Reject requests whose checksum byte doesn't match, like a ROM with checking on
+    pub fn set_strict_checksum(&self, strict: bool) {
+        self.device().strict_checksum = strict;
+    }
+
+    /// Answer the JEDEC id query with `id` instead of a Winbond part of the flash size
+    pub fn set_jedec_id(&self, id: [u8; 3]) {
+        self.device().jedec_id = id;
+    }
+
+    /// Ignore everything sent while the port is set faster than `speed`, like a slow adapter
+    pub fn set_max_baud(&self, speed: usize) {
+        self.device().max_baud = Some(speed);
+    }
+
+    /// Precede every reply to `cmd` with `polls` pending replies, like a slow erase
+    pub fn set_pending(&self, cmd: u8, polls: usize) {
+        self.device().pending.insert(cmd, polls);
+    }
+
+    /// Apply `fault` to the next request with command id `cmd`
+    pub fn inject_fault(&self, cmd: u8, fault: Fault) {
+        self.device().faults.push((cmd, fault));
+    }
+
+    pub fn flash(&self) -> Vec<u8> {
+        self.device().flash.clone()
+    }
+
+    pub fn efuse(&self) -> Vec<u8> {
+        self.device().efuse.clone()
+    }
+
+    /// A 32-bit word of the emulated address space, zero until written
+    pub fn word(&self, addr: u32) -> u32 {
+        self.device().memory.get(&addr).copied().unwrap_or(0)
+    }
+
+    pub fn set_word(&self, addr: u32, value: u32) {
+        self.device().memory.insert(addr, value);
+    }
+
+    /// Every RTS and DTR change so far, in order
+    pub fn line_changes(&self) -> Vec<(Line, bool)> {
+        self.device().lines.clone()
+    }
+
+    pub fn mode(&self) -> Mode {
+        self.device().mode
+    }
+
+    pub fn baud_rate(&self) -> BaudRate {
+        self.device().settings.baud_rate
+    }
+}
+
+impl Device {
+    fn receive(&mut self, incoming: Incoming) {
+        let mut frame = match incoming {
+            Incoming::Handshake => {
+                self.output.extend(b"OK");
+                return;
+            }
+            Incoming::Frame(frame) => frame,
+        };
+        let fault = self
+            .faults
+            .iter()
+            .position(|&(cmd, _)| cmd == frame[0])
+            .map(|i| self.faults.remove(i).1);
+        match fault {
+            Some(Fault::Garbage) => {
+                self.handle(frame[0], &frame[4..]);
+                self.output.extend(b"\x00\xff");
+                return;
+            }
+            Some(Fault::Drop) => return,
+            Some(Fault::Reset) => {
+                self.mode = Mode::BootRom;
+                self.boot_header_loaded = false;
+                return;
+            }
+            Some(Fault::Corrupt) => {
+                if let Some(byte) = frame.get_mut(4..).and_then(<[u8]>::last_mut) {
+                    *byte ^= 0x01;
+                }
+            }
+            None => {}
+        }
+        let reply = if self.strict_checksum && frame[1] != checksum(&frame[4..]) {
+            Reply::Fail(RomError::CmdCrcError)
+        } else {
+            self.handle(frame[0], &frame[4..])
+        };
+        for _ in 0..self.pending.get(&frame[0]).copied().unwrap_or(0) {
+            self.send(Reply::Fail(RomError::Polling));
+        }
+        self.send(reply);
+    }
+
+    fn send(&mut self, reply: Reply) {
+        match reply {
+            Reply::Ok => self.output.extend(b"OK"),
+            Reply::Payload(data) => {
+                self.output.extend(b"OK");
+                self.output.extend(&(data.len() as u16).to_le_bytes());
+                self.output.extend(&data);
+            }
+            Reply::Fail(code) => {
+                self.output.extend(b"FL");
+                self.output.extend(&(code as u16).to_le_bytes());
+            }
+        }
+    }
+
+    fn handle(&mut self, cmd: u8, payload: &[u8]) -> Reply {
+        match (self.mode, cmd) {
+            (Mode::BootRom, 0x10) => self.boot_info(),
+            (Mode::BootRom, 0x11) => self.load_boot_header(payload),
+            (Mode::BootRom, 0x17) => self.load_segment_header(payload),
+            (Mode::BootRom, 0x18) => self.load_segment_data(payload),
+            (Mode::BootRom, 0x19) => self.check_image(),
+            (Mode::BootRom, 0x1a) => self.run_image(),
+            (Mode::EflashLoader, 0x30) => self.flash_erase(payload),
+            (Mode::EflashLoader, 0x31) => self.flash_program(payload),
+            (Mode::EflashLoader, 0x3f) => self.flash_decompress_write(payload),
+            (Mode::EflashLoader, 0x32) => self.flash_read(payload),
+            (Mode::EflashLoader, 0x36) => self.flash_read_jedec_id(),
+            (Mode::EflashLoader, 0x3c) => self.flash_chip_erase(),
+            (Mode::EflashLoader, 0x3d) => self.sha256_read(payload),
+            (Mode::EflashLoader, 0x40) => self.efuse_write(payload),
+            (Mode::EflashLoader, 0x41) => self.efuse_read(payload),
+            (Mode::EflashLoader, 0x50) => self.mem_write(payload),
+            (Mode::EflashLoader, 0x51) => self.mem_read(payload),
+            _ => Reply::Fail(RomError::CmdIdError),
+        }
+    }
+
+    fn boot_info(&mut self) -> Reply {
+        let mut data = BOOTROM_VERSION.to_le_bytes().to_vec();
+        data.extend(&[0u8; 16]);
+        if let ChipType::BL616(_) | ChipType::BL808(_) = self.chip {
+            data.extend(&[0u8; 4]);
+        }
+        Reply::Payload(data)
+    }
+
+    fn load_boot_header(&mut self, payload: &[u8]) -> Reply {
+        let chip = self.chip.clone().to_box();
+        if payload.len() != chip.boot_header_len() {
+            return Reply::Fail(RomError::ImgBootheaderLenError);
+        }
+        self.segments_left = chip.segment_count(payload);
+        self.boot_header_loaded = true;
+        Reply::Ok
+    }
+
+    fn load_segment_header(&mut self, payload: &[u8]) -> Reply {
+        if !self.boot_header_loaded {
+            return Reply::Fail(RomError::ImgBootheaderNotLoadError);
+        }
+        if payload.len() != LOAD_SEGMENT_HEADER_LEN {
+            return Reply::Fail(RomError::ImgSectionheaderLenError);
+        }
+        if self.segments_left == 0 {
+            return Reply::Fail(RomError::ImgSegmentCntError);
+        }
+        self.segment_addr = LittleEndian::read_u32(&payload[0..4]);
+        self.segment_len = LittleEndian::read_u32(&payload[4..8]) as usize;
+        self.ram.clear();
+        Reply::Payload(payload.to_vec())
+    }
+
+    fn load_segment_data(&mut self, payload: &[u8]) -> Reply {
+        if self.ram.len() + payload.len() > self.segment_len {
+            return Reply::Fail(RomError::ImgSectiondataTlenError);
+        }
+        self.ram.extend(payload);
+        if self.ram.len() == self.segment_len {
+            for (i, word) in self.ram.chunks(4).enumerate() {
+                let mut bytes = [0u8; 4];
+                bytes[..word.len()].copy_from_slice(word);
+                self.memory
+                    .insert(self.segment_addr + i as u32 * 4, u32::from_le_bytes(bytes));
+            }
+            self.segments_left -= 1;
+        }
+        Reply::Ok
+    }
+
+    fn check_image(&mut self) -> Reply {
+        if !self.boot_header_loaded || self.segments_left > 0 || self.ram.len() != self.segment_len
+        {
+            return Reply::Fail(RomError::ImgSectiondataLenError);
+        }
+        Reply::Ok
+    }
+
+    fn run_image(&mut self) -> Reply {
+        self.mode = Mode::EflashLoader;
+        Reply::Ok
+    }
+
+    fn flash_erase(&mut self, payload: &[u8]) -> Reply {
+        if payload.len() != 8 {
+            return Reply::Fail(RomError::CmdLenError);
+        }
+        let start = LittleEndian::read_u32(&payload[0..4]) as usize;
+        let end = LittleEndian::read_u32(&payload[4..8]) as usize;
+        if start > end || end > self.flash.len() {
+            return Reply::Fail(RomError::FlashEraseParaError);
+        }
+        let start = start / SECTOR_SIZE * SECTOR_SIZE;
+        let end = (end.div_ceil(SECTOR_SIZE) * SECTOR_SIZE).min(self.flash.len());
+        self.flash[start..end].fill(0xff);
+        Reply::Ok
+    }
+
+    fn flash_chip_erase(&mut self) -> Reply {
+        self.flash.fill(0xff);
+        Reply::Ok
+    }
+
+    fn flash_program(&mut self, payload: &[u8]) -> Reply {
+        if payload.len() < 4 {
+            return Reply::Fail(RomError::CmdLenError);
+        }
+        let addr = LittleEndian::read_u32(&payload[0..4]) as usize;
+        self.program(addr, &payload[4..])
+    }
+
+    fn program(&mut self, addr: usize, data: &[u8]) -> Reply {
+        if addr + data.len() > self.flash.len() {
+            return Reply::Fail(RomError::FlashWriteAddrError);
+        }
+        // NOR flash can only clear bits
+        for (cell, byte) in self.flash[addr..].iter_mut().zip(data) {
+            *cell &= byte;
+        }
+        Reply::Ok
+    }
+
+    fn flash_decompress_write(&mut self, payload: &[u8]) -> Reply {
+        if payload.len() < 4 {
+            return Reply::Fail(RomError::CmdLenError);
+        }
+        let addr = LittleEndian::read_u32(&payload[0..4]);
+        let data = &payload[4..];
+        // a chunk at the stream's base starts a new stream, an earlier one is a retransmit
+        match &self.decompress {
+            Some(stream) if addr > stream.base && addr < stream.base + stream.received => {
+                return Reply::Ok
+            }
+            Some(stream) if addr != stream.base && addr == stream.base + stream.received => {}
+            _ => {
+                self.decompress = Some(Decompress {
+                    base: addr,
+                    received: 0,
+                    written: 0,
+                    decoder: Stream::new_stream_decoder(u64::MAX, 0).unwrap(),
+                })
+            }
+        }
+        let stream = self.decompress.as_mut().unwrap();
+        stream.received += data.len() as u32;
+        let mut output = Vec::new();
+        let mut input = data;
+        loop {
+            output.reserve(SECTOR_SIZE);
+            let total_in = stream.decoder.total_in();
+            match stream.decoder.process_vec(input, &mut output, Action::Run) {
+                Ok(Status::StreamEnd) => break,
+                Ok(_) => {}
+                Err(_) => return Reply::Fail(RomError::ImgSectiondataDecError),
+            }
+            input = &input[(stream.decoder.total_in() - total_in) as usize..];
+            if input.is_empty() && output.len() < output.capacity() {
+                break;
+            }
+        }
+        let addr = stream.base as usize + stream.written;
+        stream.written += output.len();
+        self.program(addr, &output)
+    }
+
+    fn flash_read_jedec_id(&mut self) -> Reply {
+        let mut id = self.jedec_id.to_vec();
+        id.push(0x00);
+        Reply::Payload(id)
+    }
+
+    fn flash_read(&mut self, payload: &[u8]) -> Reply {
+        match self.flash_range(payload) {
+            Some((addr, size)) => Reply::Payload(self.flash[addr..addr + size].to_vec()),
+            None => Reply::Fail(RomError::FlashInitError),
+        }
+    }
+
+    fn sha256_read(&mut self, payload: &[u8]) -> Reply {
+        match self.flash_range(payload) {
+            Some((addr, len)) => {
+                Reply::Payload(Sha256::digest(&self.flash[addr..addr + len]).to_vec())
+            }
+            None => Reply::Fail(RomError::FlashInitError),
+        }
+    }
+
+    fn efuse_write(&mut self, payload: &[u8]) -> Reply {
+        if payload.len() < 4 {
+            return Reply::Fail(RomError::CmdLenError);
+        }
+        let addr = LittleEndian::read_u32(&payload[0..4]) as usize;
+        let data = &payload[4..];
+        if addr + data.len() > self.efuse.len() {
+            return Reply::Fail(RomError::Fail);
+        }
+        // blown bits stay blown
+        for (cell, byte) in self.efuse[addr..].iter_mut().zip(data) {
+            *cell |= byte;
+        }
+        Reply::Ok
+    }
+
+    fn efuse_read(&mut self, payload: &[u8]) -> Reply {
+        if payload.len() != 8 {
+            return Reply::Fail(RomError::CmdLenError);
+        }
+        let addr = LittleEndian::read_u32(&payload[0..4]) as usize;
+        let len = LittleEndian::read_u32(&payload[4..8]) as usize;
+        if addr + len > self.efuse.len() {
+            return Reply::Fail(RomError::Fail);
+        }
+        Reply::Payload(self.efuse[addr..addr + len].to_vec())
+    }
+
+    fn mem_write(&mut self, payload: &[u8]) -> Reply {
+        if payload.len() != 8 {
+            return Reply::Fail(RomError::CmdLenError);
+        }
+        let addr = LittleEndian::read_u32(&payload[0..4]);
+        let value = LittleEndian::read_u32(&payload[4..8]);
+        self.memory.insert(addr, value);
+        Reply::Ok
+    }
+
+    fn mem_read(&mut self, payload: &[u8]) -> Reply {
+        if payload.len() != 8 {
+            return Reply::Fail(RomError::CmdLenError);
+        }
+        let addr = LittleEndian::read_u32(&payload[0..4]);
+        let len = LittleEndian::read_u32(&payload[4..8]);
+        let data = (addr..addr + len)
+            .step_by(4)
+            .flat_map(|addr| self.memory.get(&addr).copied().unwrap_or(0).to_le_bytes())
+            .collect();
+        Reply::Payload(data)
+    }
+
+    fn flash_range(&self, payload: &[u8]) -> Option<(usize, usize)> {
+        if payload.len() != 8 {
+            return None;
+        }
+        let addr = LittleEndian::read_u32(&payload[0..4]) as usize;
+        let len = LittleEndian::read_u32(&payload[4..8]) as usize;
+        if addr + len > self.flash.len() {
+            return None;
+        }
+        Some((addr, len))
+    }
+}
+
+impl Read for Emulator {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut device = self.device();
+        if device.output.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::TimedOut, "emulator timeout"));
+        }
+        let size = buf.len().min(device.output.len());
+        for (dst, src) in buf.iter_mut().zip(device.output.drain(..size)) {
+            *dst = src;
+        }
+        Ok(size)
+    }
+}
+
+impl Write for Emulator {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut device = self.device();
+        let speed = device.settings.baud_rate.speed();
+        if device.max_baud.is_some_and(|max| speed > max) {
+            return Ok(buf.len());
+        }
+        for incoming in device.framer.push(buf) {
+            device.receive(incoming);
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl SerialPort for Emulator {
+    fn timeout(&self) -> Duration {
+        self.device().timeout
+    }
+
+    fn set_timeout(&mut self, timeout: Duration) -> serial::Result<()> {
+        self.device().timeout = timeout;
+        Ok(())
+    }
+
+    fn configure(&mut self, settings: &PortSettings) -> serial::Result<()> {
+        self.device().settings = *settings;
+        Ok(())
+    }
+
+    fn reconfigure(
+        &mut self,
+        setup: &dyn Fn(&mut dyn SerialPortSettings) -> serial::Result<()>,
+    ) -> serial::Result<()> {
+        let mut device = self.device();
+        setup(&mut device.settings)
+    }
+
+    fn set_rts(&mut self, level: bool) -> serial::Result<()> {
+        self.device().lines.push((Line::Rts, level));
+        Ok(())
+    }
+
+    fn set_dtr(&mut self, level: bool) -> serial::Result<()> {
+        self.device().lines.push((Line::Dtr, level));
+        Ok(())
+    }
+
+    fn read_cts(&mut self) -> serial::Result<bool> {
+        Ok(false)
+    }
+
+    fn read_dsr(&mut self) -> serial::Result<bool> {
+        Ok(false)
+    }
+
+    fn read_ri(&mut self) -> serial::Result<bool> {
+        Ok(false)
+    }
+
+    fn read_cd(&mut self) -> serial::Result<bool> {
+        Ok(false)
+    }
+}
@@ -10,6 +10,8 @@ pub enum Error {
     IO(#[from] std::io::Error),
     #[error("Failed to connect to the device")]
     ConnectionFailed,
+    #[error("No known serial adapter found, pass one with --port")]
+    NoSerialPort,
     #[error("Timeout while running command")]
     Timeout,
     #[error("Invalid args")]
@@ -20,18 +22,58 @@ pub enum Error {
     OverSizedPacket,
     #[error("elf image is not valid")]
     InvalidElf,
+    #[error("invalid {format} record at {line}")]
+    InvalidRecord { format: &'static str, line: usize },
+    #[error("image has no data to flash")]
+    EmptyImage,
     #[error("elf image can not be ran from ram")]
     ElfNotRamLoadable,
-    #[error("chip not recognized")]
+    #[error("chip not recognized, pass it with --chip")]
     UnrecognizedChip,
+    #[error("boot info could be {0}, pass the chip with --chip")]
+    AmbiguousChip(String),
+    #[error("unknown chip {0}, expected auto, bl602, bl616, bl702 or bl808")]
+    UnknownChip(String),
+    #[error("no eflash_loader is bundled for {0}, pass one from its SDK with --eflash-loader")]
+    MissingEflashLoader(&'static str),
+    #[error("no boot2 is bundled for this chip, pass one with --boot2 or use --without-boot2")]
+    MissingBoot2,
+    #[error("no {0} core on this chip")]
+    UnsupportedCore(String),
+    #[error("partition {0} not found in the partition table")]
+    MissingPartition(String),
+    #[error("image of {len:#x} bytes doesn't fit partition {name} of {size:#x} bytes")]
+    PartitionOverflow { name: String, len: u32, size: u32 },
     #[error("flash chip not supported, flash id: {0:#x}")]
     UnsupportedFlash(u8),
+    #[error("efuse bits at {0:#x} are already blown and can't be cleared")]
+    EfuseBlown(u32),
+    #[error("efuse verify failed at {0:#x}")]
+    EfuseVerify(u32),
+    #[error("flash contents at {0:#x} don't match the image after programming")]
+    VerifyFailed(u32),
+    #[error("refusing to program efuse without --confirm")]
+    ConfirmationRequired,
+    #[error("segment {addr:#x}+{size:#x} exceeds flash size {flash_size:#x}")]
+    SegmentExceedsFlash {
+        addr: u32,
+        size: u32,
+        flash_size: u32,
+    },
+    #[error("range {addr:#x}+{size:#x} runs past the end of the address space")]
+    RangeOverflow { addr: u32, size: u32 },
+    #[error("{failed} of {total} devices failed")]
+    DevicesFailed { failed: usize, total: usize },
+    #[error("chip rejected a command checksum, the serial link is corrupting data")]
+    ChecksumRejected,
     #[error("ROM error {0:?}")]
     RomError(RomError),
     #[error("Parse error")]
     ParseError(#[from] deku::error::DekuError),
     #[error("Parse toml error")]
     TomlError(#[from] toml::de::Error),
+    #[error("Compression error: {0}")]
+    Compress(#[from] xz2::stream::Error),
 }
 
 #[derive(Copy, Clone, Debug, TryFromPrimitive)]
@@ -1,15 +1,135 @@
-use crate::chip::{Chip, ChipType};
-use crate::Error;
-use crate::{connection::Connection, elf::RomSegment};
+use crate::chip::{Bl602, Bl616, Bl702, Bl808, ChipType};
+use crate::{
+    connection::Connection, elf::RomSegment, reset::ResetConfig, trace::TraceRecorder,
+    transport::Transport,
+};
+use crate::{Error, RomError};
+use byteorder::{ByteOrder, LittleEndian};
 use indicatif::{HumanBytes, ProgressBar, ProgressStyle};
-use log::warn;
-use serial::{BaudRate, SerialPort};
+use serial::BaudRate;
 use sha2::{Digest, Sha256};
 use std::{
     io::{Cursor, Read, Write},
     time::{Duration, Instant},
 };
 use std::{ops::Range, thread::sleep};
+use xz2::{
+    read::XzEncoder,
+    stream::{Check, Filters, LzmaOptions, Stream},
+};
+
+const PROGRAM_CHUNK_SIZE: usize = 4000;
+const SECTOR_SIZE: u32 = 4096;
+/// Read timeout for commands that don't touch flash, and the slack added to flash timeouts
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(2);
+/// Hashing speed of the loader, it reads flash at tens of MB/s
+const SHA256_TIME_PER_MB: Duration = Duration::from_millis(500);
+/// Attempts per chunk before treating the link as lost
+const PROGRAM_RETRIES: usize = 3;
+/// Reconnects per segment before giving up
+const MAX_RESUMES: usize = 3;
+/// Requests kept in flight when pipelining, a loader buffers little while writing flash
+const PROGRAM_PIPELINE_DEPTH: usize = 2;
+const READ_PIPELINE_DEPTH: usize = 8;
+const READ_BLOCK_SIZE: u32 = 4096;
+/// Rates tried below the requested flash speed when the link can't keep up
+const FALLBACK_BAUD_RATES: [usize; 7] = [
+    1_500_000, 1_000_000, 921_600, 500_000, 460_800, 230_400, 115_200,
+];
+
+/// Worst-case flash operation times, from a boot header's flash config
+#[derive(Copy, Clone, Debug)]
+pub struct FlashTiming {
+    pub sector_erase: Duration,
+    pub blk32k_erase: Duration,
+    pub blk64k_erase: Duration,
+    pub chip_erase: Duration,
+    pub page_program: Duration,
+    pub page_size: u32,
+}
+
+impl Default for FlashTiming {
+    /// The timing in the default BL602 boot header config
+    fn default() -> Self {
+        FlashTiming {
+            sector_erase: Duration::from_millis(300),
+            blk32k_erase: Duration::from_millis(1200),
+            blk64k_erase: Duration::from_millis(1200),
+            chip_erase: Duration::from_millis(20000),
+            page_program: Duration::from_millis(5),
+            page_size: 256,
+        }
+    }
+}
+
+impl FlashTiming {
+    /// Time to erase `range`, using the largest aligned block that fits at each step
+    pub fn erase(&self, range: Range<u32>) -> Duration {
+        let blocks = [
+            (0x10000, self.blk64k_erase),
+            (0x8000, self.blk32k_erase),
+            (SECTOR_SIZE, self.sector_erase),
+        ];
+        let mut time = Duration::ZERO;
+        let mut addr = range.start - range.start % SECTOR_SIZE;
+        while addr < range.end {
+            let (size, block_time) = blocks
+                .iter()
+                .copied()
+                .find(|&(size, _)| addr % size == 0 && addr + size <= range.end)
+                .unwrap_or((SECTOR_SIZE, self.sector_erase));
+            time += block_time;
+            addr += size;
+        }
+        time
+    }
+
+    pub fn program(&self, len: u32) -> Duration {
+        self.page_program * len.div_ceil(self.page_size.max(1))
+    }
+}
+
+/// Read timeout for an operation expected to take up to `time`
+fn with_margin(time: Duration) -> Duration {
+    time * 3 / 2 + COMMAND_TIMEOUT
+}
+
+/// The SPI flash found behind the chip
+#[derive(Copy, Clone, Debug)]
+pub struct FlashInfo {
+    pub jedec_id: [u8; 3],
+    pub size: u32,
+}
+
+impl FlashInfo {
+    pub fn from_jedec_id(jedec_id: [u8; 3]) -> Result<Self, Error> {
+        // the capacity byte is log2 of the size in bytes for every vendor we know
+        let capacity = jedec_id[2];
+        if !(0x10..=0x19).contains(&capacity) {
+            return Err(Error::UnsupportedFlash(capacity));
+        }
+        Ok(FlashInfo {
+            jedec_id,
+            size: 1 << capacity,
+        })
+    }
+
+    pub fn manufacturer(&self) -> &'static str {
+        match self.jedec_id[0] {
+            0x0b => "XTX",
+            0x1c => "EON",
+            0x20 => "XMC",
+            0x5e => "Zbit",
+            0x68 => "Boya",
+            0x85 => "Puya",
+            0x9d => "ISSI",
+            0xc2 => "Macronix",
+            0xc8 => "GigaDevice",
+            0xef => "Winbond",
+            _ => "Unknown",
+        }
+    }
+}
 
 fn get_bar(len: u64) -> ProgressBar {
     let bar = ProgressBar::new(len);
@@ -21,60 +141,258 @@ fn get_bar(len: u64) -> ProgressBar {
     bar
 }
 
+fn get_spinner(message: &'static str) -> ProgressBar {
+    let spinner = ProgressBar::new_spinner();
+    spinner.set_style(ProgressStyle::default_spinner().template("  {spinner} {msg} {elapsed}  "));
+    spinner.set_message(message);
+    spinner
+}
+
 pub struct Flasher {
     connection: Connection,
     boot_info: protocol::BootInfoV2,
-    chip: Box<dyn Chip>,
+    chip: ChipType,
+    initial_speed: BaudRate,
     flash_speed: BaudRate,
+    loader_running: bool,
+    flash_info: Option<FlashInfo>,
+    compress: bool,
+    pipeline: bool,
+    timing: FlashTiming,
+    progress: Option<ProgressBar>,
+    eflash_loader: Option<Vec<u8>>,
+}
+
+/// Compress `data` into an xz stream for the loader's decompress-and-write command
+fn compress(data: &[u8]) -> Result<Vec<u8>, Error> {
+    // the loader decompresses into a small RAM buffer, so keep the dictionary small
+    let mut options = LzmaOptions::new_preset(9)?;
+    options.dict_size(32 * 1024);
+    let mut filters = Filters::new();
+    filters.lzma2(&options);
+    let stream = Stream::new_stream_encoder(&filters, Check::Crc32)?;
+
+    let mut compressed = Vec::new();
+    XzEncoder::new_stream(data, stream).read_to_end(&mut compressed)?;
+    Ok(compressed)
+}
+
+/// Errors caused by the serial link rather than by the request itself
+fn is_link_error(e: &Error) -> bool {
+    matches!(
+        e,
+        Error::RespError
+            | Error::Timeout
+            | Error::IO(_)
+            | Error::Serial(_)
+            | Error::RomError(
+                RomError::CmdIdError
+                    | RomError::CmdLenError
+                    | RomError::CmdCrcError
+                    | RomError::CmdSeqError
+            )
+    )
+}
+
+/// Chips answering with `boot_info`, told apart by its length and the boot ROM version
+fn chip_candidates(boot_info: &protocol::BootInfoV2) -> &'static [ChipType] {
+    match (boot_info.len, boot_info.bootrom_version) {
+        // BL616 and BL808 append a word the older boot ROMs don't send
+        (24, _) => &[ChipType::BL616(Bl616), ChipType::BL808(Bl808)],
+        // nothing in the reply tells BL602 from BL70x
+        (20, 1) => &[ChipType::BL602(Bl602), ChipType::BL702(Bl702)],
+        _ => &[],
+    }
 }
 
 impl Flasher {
+    /// Connect to the boot ROM, identifying the chip from its boot info if `chip` is `None`
     pub fn connect(
-        chip: ChipType,
-        serial: impl SerialPort + 'static,
+        chip: impl Into<Option<ChipType>>,
+        transport: impl Transport + 'static,
         initial_speed: BaudRate,
         flash_speed: BaudRate,
-        reset_pin: String,
-        boot_pin: String,
+        reset: ResetConfig,
+        trace: Option<TraceRecorder>,
     ) -> Result<Self, Error> {
         let mut flasher = Flasher {
-            connection: Connection::new(serial, reset_pin, boot_pin),
+            connection: Connection::new(transport, reset),
             boot_info: protocol::BootInfoV2::default(),
-            chip: chip.clone().to_box(),
+            chip: ChipType::BL602(Bl602),
+            initial_speed,
             flash_speed,
+            loader_running: false,
+            flash_info: None,
+            compress: true,
+            pipeline: true,
+            timing: FlashTiming::default(),
+            progress: None,
+            eflash_loader: None,
         };
+        if let Some(trace) = trace {
+            flasher.connection.set_trace(trace);
+        }
         flasher.connection.set_baud(initial_speed)?;
         flasher.start_connection()?;
-        flasher.connection.set_timeout(Duration::from_secs(10))?;
-        flasher.boot_info = flasher.boot_rom().get_boot_info(chip)?;
+        flasher.connection.set_timeout(COMMAND_TIMEOUT)?;
+        flasher.boot_info = flasher.boot_rom().get_boot_info()?;
+        let candidates = chip_candidates(&flasher.boot_info);
+        flasher.chip = match (chip.into(), candidates) {
+            (Some(chip), _) => {
+                if !candidates.iter().any(|c| c.name() == chip.name()) {
+                    log::warn!(
+                        "Boot info doesn't look like {}, using it as given",
+                        chip.name()
+                    );
+                }
+                chip
+            }
+            (None, [chip]) => {
+                log::info!("Detected chip {}", chip.name());
+                chip.clone()
+            }
+            (None, []) => return Err(Error::UnrecognizedChip),
+            (None, _) => {
+                let names: Vec<_> = candidates.iter().map(ChipType::name).collect();
+                return Err(Error::AmbiguousChip(names.join(" or ")));
+            }
+        };
 
         Ok(flasher)
     }
 
+    pub fn chip(&self) -> ChipType {
+        self.chip.clone()
+    }
+
     pub fn into_inner(self) -> Connection {
         self.connection
     }
 
+    /// Send segments as xz streams when that's smaller, on by default
+    pub fn set_compress(&mut self, compress: bool) {
+        self.compress = compress;
+    }
+
+    /// Flash timing used for erase and program timeouts
+    pub fn set_flash_timing(&mut self, timing: FlashTiming) {
+        self.timing = timing;
+    }
+
+    /// Fail when the chip rejects a command checksum instead of sending the command
+    /// again, off by default. Whether the chip checks checksums at all is up to its
+    /// boot ROM and eflash_loader, this can't turn checking on.
+    pub fn set_fatal_checksum_errors(&mut self, fatal: bool) {
+        self.connection.set_fatal_checksum_errors(fatal);
+    }
+
+    /// Load this eflash_loader RAM image instead of the one bundled for the chip
+    pub fn set_eflash_loader(&mut self, loader: Vec<u8>) {
+        self.eflash_loader = Some(loader);
+    }
+
+    /// Keep several program and read requests in flight, on by default
+    pub fn set_pipeline(&mut self, pipeline: bool) {
+        self.pipeline = pipeline;
+    }
+
+    /// Report progress on `bar`, like a line of a multi-device view, instead of
+    /// drawing bars of its own. The caller finishes it.
+    pub fn set_progress_bar(&mut self, bar: ProgressBar) {
+        self.progress = Some(bar);
+    }
+
+    fn bar(&self, len: u64, message: &'static str) -> ProgressBar {
+        match &self.progress {
+            Some(bar) => {
+                bar.set_message(message);
+                bar.set_length(len);
+                bar.set_position(0);
+                bar.clone()
+            }
+            None => get_bar(len),
+        }
+    }
+
+    fn spinner(&self, message: &'static str) -> ProgressBar {
+        match &self.progress {
+            Some(bar) => {
+                bar.set_message(message);
+                bar.clone()
+            }
+            None => get_spinner(message),
+        }
+    }
+
+    fn finish(&self, bar: &ProgressBar) {
+        if self.progress.is_none() {
+            bar.finish_and_clear();
+        }
+    }
+
     pub fn boot_info(&self) -> &protocol::BootInfoV2 {
         &self.boot_info
     }
 
+    /// Query the flash JEDEC id, loading the eflash_loader if needed
+    pub fn flash_info(&mut self) -> Result<FlashInfo, Error> {
+        if let Some(info) = self.flash_info {
+            return Ok(info);
+        }
+        self.load_eflash_loader()?;
+
+        let info = FlashInfo::from_jedec_id(self.eflash_loader().flash_read_jedec_id()?)?;
+        log::info!(
+            "Flash: {} {} {}",
+            info.manufacturer(),
+            hex::encode(info.jedec_id),
+            HumanBytes(info.size as u64)
+        );
+        self.flash_info = Some(info);
+        Ok(info)
+    }
+
+    /// The flash size to check writes against, `None` if the JEDEC id doesn't give it
+    fn flash_size(&mut self) -> Result<Option<u32>, Error> {
+        match self.flash_info() {
+            Ok(info) => Ok(Some(info.size)),
+            Err(Error::UnsupportedFlash(capacity)) => {
+                log::warn!(
+                    "Unknown flash capacity byte {:#04x}, not checking against the flash size",
+                    capacity
+                );
+                Ok(None)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
     pub fn load_segments<'a>(
         &'a mut self,
         force: bool,
         segments: impl Iterator<Item = RomSegment<'a>>,
     ) -> Result<(), Error> {
-        self.load_eflash_loader()?;
+        let flash_size = self.flash_size()?;
+        let segments: Vec<_> = segments.collect();
+        if let Some(flash_size) = flash_size {
+            for segment in &segments {
+                if segment.addr as u64 + segment.size() as u64 > flash_size as u64 {
+                    return Err(Error::SegmentExceedsFlash {
+                        addr: segment.addr,
+                        size: segment.size(),
+                        flash_size,
+                    });
+                }
+            }
+        }
 
         for segment in segments {
             let local_hash = Sha256::digest(&segment.data[0..segment.size() as usize]);
 
             // skip segment if the contents are matched
             if !force {
-                let sha256 = self
-                    .eflash_loader()
-                    .sha256_read(segment.addr, segment.size())?;
-                if sha256 == &local_hash[..] {
+                let sha256 = self.sha256_read(segment.addr, segment.size())?;
+                if sha256 == local_hash[..] {
                     log::info!(
                         "Skip segment addr: {:x} size: {} sha256 matches",
                         segment.addr,
@@ -89,25 +407,32 @@ impl Flasher {
                 segment.addr,
                 segment.size()
             );
-            self.eflash_loader()
-                .flash_erase(segment.addr, segment.addr + segment.size())?;
-
-            let mut reader = Cursor::new(&segment.data);
-            let mut cur = segment.addr;
+            self.erase(segment.addr..segment.addr + segment.size())?;
 
+            let compressed = if self.compress {
+                Some(compress(&segment.data)?)
+                    .filter(|compressed| compressed.len() < segment.data.len())
+            } else {
+                None
+            };
             let start = Instant::now();
             log::info!("Program flash... {:x}", local_hash);
-            let pb = get_bar(segment.size() as u64);
-            loop {
-                let size = self.eflash_loader().flash_program(cur, &mut reader)?;
-                // log::trace!("program {:x} {:x}", cur, size);
-                cur += size;
-                pb.inc(size as u64);
-                if size == 0 {
-                    break;
+            match &compressed {
+                Some(compressed) => {
+                    log::info!(
+                        "Compressed {} to {}",
+                        HumanBytes(segment.size() as u64),
+                        HumanBytes(compressed.len() as u64)
+                    );
+                    // any chunk may expand to the rest of the segment
+                    let timeout = with_margin(self.timing.program(segment.size()));
+                    self.program_data(segment.addr, compressed, true, timeout)?;
+                }
+                None => {
+                    let timeout = with_margin(self.timing.program(PROGRAM_CHUNK_SIZE as u32));
+                    self.program_data(segment.addr, &segment.data, false, timeout)?;
                 }
             }
-            pb.finish_and_clear();
             let elapsed = start.elapsed();
             log::info!(
                 "Program done {:?} {}/s",
@@ -115,15 +440,14 @@ impl Flasher {
                 HumanBytes((segment.size() as f64 / elapsed.as_millis() as f64 * 1000.0) as u64)
             );
 
-            let sha256 = self
-                .eflash_loader()
-                .sha256_read(segment.addr, segment.size())?;
-            if sha256 != &local_hash[..] {
-                log::warn!(
+            let sha256 = self.sha256_read(segment.addr, segment.size())?;
+            if sha256 != local_hash[..] {
+                log::error!(
                     "sha256 not match: {} != {}",
                     hex::encode(sha256),
                     hex::encode(local_hash)
                 );
+                return Err(Error::VerifyFailed(segment.addr));
             }
         }
         Ok(())
@@ -138,10 +462,8 @@ impl Flasher {
         for segment in segments {
             let local_hash = Sha256::digest(&segment.data[0..segment.size() as usize]);
 
-            let sha256 = self
-                .eflash_loader()
-                .sha256_read(segment.addr, segment.size())?;
-            if sha256 != &local_hash[..] {
+            let sha256 = self.sha256_read(segment.addr, segment.size())?;
+            if sha256 != local_hash[..] {
                 log::warn!(
                     "{:x} sha256 not match: {} != {}",
                     segment.addr,
@@ -158,40 +480,295 @@ impl Flasher {
     pub fn dump_flash(&mut self, range: Range<u32>, mut writer: impl Write) -> Result<(), Error> {
         self.load_eflash_loader()?;
 
-        const BLOCK_SIZE: usize = 4096;
-        let mut cur = range.start;
-        let pb = get_bar(range.len() as u64);
-        while cur < range.end {
-            let data = self
+        let mut dump = Vec::with_capacity(range.len());
+        let pb = self.bar(range.len() as u64, "Reading");
+        if self.pipeline {
+            let result = self
                 .eflash_loader()
-                .flash_read(cur, (range.end - cur).min(BLOCK_SIZE as u32))?;
-            writer.write_all(&data)?;
-            cur += data.len() as u32;
+                .flash_read_pipelined(range.clone(), |data| {
+                    pb.inc(data.len() as u64);
+                    dump.extend(data);
+                    Ok(())
+                });
+            if let Err(e) = result {
+                if !is_link_error(&e) {
+                    return Err(e);
+                }
+                // a lost response shifts every one after it onto the wrong request,
+                // so none of the blocks read so far can be trusted
+                log::warn!("Pipelined read failed: {}, reading again in lock-step", e);
+                dump.clear();
+                pb.set_position(0);
+                self.pipeline = false;
+            }
+        }
+        let mut cur = range.start + dump.len() as u32;
+        while cur < range.end {
+            let size = (range.end - cur).min(READ_BLOCK_SIZE);
+            let data = self.eflash_loader().flash_read(cur, size)?;
+            cur += size;
             pb.inc(data.len() as u64);
+            dump.extend(data);
+        }
+        self.finish(&pb);
+        writer.write_all(&dump)?;
+
+        Ok(())
+    }
+
+    /// Erase `range` widened to whole sectors, returning the range actually erased
+    pub fn erase_flash(&mut self, range: Range<u32>) -> Result<Range<u32>, Error> {
+        let flash_size = self.flash_size()?;
+        let start = range.start / SECTOR_SIZE * SECTOR_SIZE;
+        let end = range
+            .end
+            .div_ceil(SECTOR_SIZE)
+            .checked_mul(SECTOR_SIZE)
+            .ok_or(Error::RangeOverflow {
+                addr: range.start,
+                size: range.end.saturating_sub(range.start),
+            })?;
+        if start >= end {
+            return Err(Error::ArgsError);
+        }
+        if let Some(flash_size) = flash_size {
+            if end > flash_size {
+                return Err(Error::SegmentExceedsFlash {
+                    addr: start,
+                    size: end - start,
+                    flash_size,
+                });
+            }
         }
-        pb.finish_and_clear();
+
+        log::info!("Erase flash {:x}..{:x}", start, end);
+        self.erase(start..end)?;
+
+        Ok(start..end)
+    }
+
+    fn erase(&mut self, range: Range<u32>) -> Result<(), Error> {
+        let timeout = with_margin(self.timing.erase(range.clone()));
+        log::debug!("Erase timeout {:?}", timeout);
+        let spinner = self.spinner("Erasing");
+        // the timeout covers loaders that stay silent until they are done,
+        // the deadline those that keep replying they're busy
+        self.connection.with_timeout(timeout, |connection| {
+            EflashLoader(connection)
+                .flash_erase(range.start, range.end, timeout, |_| spinner.tick())
+        })?;
+        self.finish(&spinner);
 
         Ok(())
     }
 
+    fn sha256_read(&mut self, addr: u32, len: u32) -> Result<[u8; 32], Error> {
+        let timeout = COMMAND_TIMEOUT + SHA256_TIME_PER_MB * len.div_ceil(0x100000);
+        self.connection.with_timeout(timeout, |connection| {
+            EflashLoader(connection).sha256_read(addr, len)
+        })
+    }
+
+    pub fn erase_chip(&mut self) -> Result<(), Error> {
+        self.load_eflash_loader()?;
+
+        log::info!("Erase whole flash...");
+        let timeout = with_margin(self.timing.chip_erase);
+        let spinner = self.spinner("Erasing");
+        self.connection.with_timeout(timeout, |connection| {
+            EflashLoader(connection).flash_chip_erase(timeout, |_| spinner.tick())
+        })?;
+        self.finish(&spinner);
+
+        Ok(())
+    }
+
+    pub fn read_memory(&mut self, addr: u32, len: u32) -> Result<Vec<u8>, Error> {
+        if addr % 4 != 0 || len % 4 != 0 {
+            return Err(Error::ArgsError);
+        }
+        let end = addr.checked_add(len).ok_or(Error::ArgsError)?;
+        self.load_eflash_loader()?;
+
+        const BLOCK_SIZE: u32 = 1024;
+        let mut data = Vec::with_capacity(len as usize);
+        let mut cur = addr;
+        while cur < end {
+            let size = (end - cur).min(BLOCK_SIZE);
+            let block = self.eflash_loader().mem_read(cur, size)?;
+            if block.len() != size as usize {
+                return Err(Error::RespError);
+            }
+            data.extend(block);
+            cur += size;
+        }
+        Ok(data)
+    }
+
+    pub fn write_word(&mut self, addr: u32, value: u32) -> Result<(), Error> {
+        if addr % 4 != 0 {
+            return Err(Error::ArgsError);
+        }
+        self.load_eflash_loader()?;
+
+        self.eflash_loader().mem_write(addr, value)
+    }
+
+    pub fn read_efuse(&mut self, addr: u32, len: u32) -> Result<Vec<u8>, Error> {
+        if addr % 4 != 0 || len % 4 != 0 {
+            return Err(Error::ArgsError);
+        }
+        self.load_eflash_loader()?;
+
+        self.eflash_loader().efuse_read(addr, len)
+    }
+
+    /// Blow the bits of `data` selected by `mask`, then read them back
+    pub fn program_efuse(&mut self, addr: u32, data: &[u8], mask: &[u8]) -> Result<(), Error> {
+        if addr % 4 != 0 || data.len() % 4 != 0 || data.len() != mask.len() {
+            return Err(Error::ArgsError);
+        }
+        self.load_eflash_loader()?;
+
+        let data: Vec<u8> = data.iter().zip(mask).map(|(d, m)| d & m).collect();
+        let len = data.len() as u32;
+        let current = self.eflash_loader().efuse_read(addr, len)?;
+        if current.len() != data.len() {
+            return Err(Error::RespError);
+        }
+        if let Some(i) = (0..data.len()).find(|&i| current[i] & mask[i] & !data[i] != 0) {
+            return Err(Error::EfuseBlown(addr + i as u32));
+        }
+
+        log::info!(
+            "Program efuse addr: {:x} data: {}",
+            addr,
+            hex::encode(&data)
+        );
+        self.eflash_loader().efuse_write(addr, &data)?;
+
+        let written = self.eflash_loader().efuse_read(addr, len)?;
+        if written.len() != data.len() {
+            return Err(Error::RespError);
+        }
+        if let Some(i) = (0..data.len()).find(|&i| written[i] & mask[i] != data[i]) {
+            return Err(Error::EfuseVerify(addr + i as u32));
+        }
+        Ok(())
+    }
+
     pub fn load_eflash_loader(&mut self) -> Result<(), Error> {
-        let input = self.chip.get_eflash_loader().to_vec();
-        let len = input.len();
-        let mut reader = Cursor::new(input);
-        self.boot_rom().load_boot_header(&mut reader)?;
-        self.boot_rom().load_segment_header(&mut reader)?;
+        if self.loader_running {
+            return Ok(());
+        }
+        let input = match &self.eflash_loader {
+            Some(loader) => loader.clone(),
+            None => self
+                .chip
+                .clone()
+                .to_box()
+                .get_eflash_loader()
+                .ok_or_else(|| Error::MissingEflashLoader(self.chip.name()))?
+                .to_vec(),
+        };
+        log::info!("Sending eflash_loader...");
+        self.load_ram_image(&input)?;
+        sleep(Duration::from_millis(500));
+        if self.connection.can_set_baud() {
+            self.negotiate_baud()?;
+            log::info!("Entered eflash_loader at {} baud", self.flash_speed.speed());
+        } else {
+            // the rate is whatever the other end of the link is set to
+            self.handshake()?;
+            log::info!("Entered eflash_loader");
+        }
+        self.loader_running = true;
+
+        Ok(())
+    }
+
+    /// The baud rate the eflash_loader is talked to at, lowered if the link couldn't keep up.
+    /// `None` if the transport doesn't control the line rate.
+    pub fn baud_rate(&self) -> Option<BaudRate> {
+        Some(self.flash_speed).filter(|_| self.connection.can_set_baud())
+    }
+
+    /// Handshake at the flash speed, stepping down through the fallback rates until one works
+    fn negotiate_baud(&mut self) -> Result<(), Error> {
+        let requested = self.flash_speed.speed();
+        let rates = std::iter::once(requested).chain(
+            FALLBACK_BAUD_RATES
+                .iter()
+                .copied()
+                .filter(|&rate| rate < requested),
+        );
+        for rate in rates {
+            let speed = BaudRate::from_speed(rate);
+            self.connection.set_baud(speed)?;
+            if self.handshake().is_ok() {
+                if rate != requested {
+                    log::warn!("Handshake failed at {} baud, using {}", requested, rate);
+                }
+                self.flash_speed = speed;
+                return Ok(());
+            }
+            log::debug!("Handshake failed at {} baud", rate);
+        }
+        Err(Error::ConnectionFailed)
+    }
+
+    /// Drop to the next fallback rate, returns false if already at the lowest
+    fn step_down_baud(&mut self) -> bool {
+        if !self.connection.can_set_baud() {
+            return false;
+        }
+        let current = self.flash_speed.speed();
+        match FALLBACK_BAUD_RATES.iter().find(|&&rate| rate < current) {
+            Some(&rate) => {
+                log::warn!("Lowering baud rate from {} to {}", current, rate);
+                self.flash_speed = BaudRate::from_speed(rate);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Load an image built by `BootHeaderCfg::make_ram_image` into RAM and jump to it
+    pub fn run_ram_image(&mut self, image: &[u8]) -> Result<(), Error> {
+        if self.loader_running {
+            self.start_connection()?;
+        }
+        log::info!("Sending image...");
+        self.load_ram_image(image)
+    }
+
+    fn load_ram_image(&mut self, image: &[u8]) -> Result<(), Error> {
+        let chip = self.chip.clone().to_box();
+        let header_len = chip.boot_header_len();
+        if image.len() < header_len {
+            return Err(Error::ArgsError);
+        }
+        let segment_count = chip.segment_count(&image[..header_len]);
+        let len = image.len();
+        let mut reader = Cursor::new(image);
+        self.boot_rom().load_boot_header(&mut reader, header_len)?;
 
         let start = Instant::now();
-        log::info!("Sending eflash_loader...");
-        let pb = get_bar(len as u64);
-        loop {
-            let size = self.boot_rom().load_segment_data(&mut reader)?;
-            pb.inc(size as u64);
-            if size == 0 {
-                break;
+        let pb = self.bar(len as u64, "Loading");
+        for _ in 0..segment_count {
+            self.boot_rom().load_segment_header(&mut reader)?;
+            let pos = reader.position() as usize;
+            let segment_len = LittleEndian::read_u32(&image[pos - 12..pos - 8]);
+            let mut segment = (&mut reader).take(segment_len as u64);
+            loop {
+                let size = self.boot_rom().load_segment_data(&mut segment)?;
+                pb.inc(size as u64);
+                if size == 0 {
+                    break;
+                }
             }
         }
-        pb.finish_and_clear();
+        self.finish(&pb);
         let elapsed = start.elapsed();
         log::info!(
             "Finished {:?} {}/s",
@@ -201,24 +778,152 @@ impl Flasher {
 
         self.boot_rom().check_image()?;
         self.boot_rom().run_image()?;
-        sleep(Duration::from_millis(500));
-        self.connection.set_baud(self.flash_speed)?;
-        self.handshake()?;
 
-        log::info!("Entered eflash_loader");
+        Ok(())
+    }
+
+    /// Program `data` at `addr` chunk by chunk, resuming after the link drops.
+    ///
+    /// A compressed stream can't be resumed in the middle, so it restarts from its start.
+    /// `timeout` is how long to wait for the reply to each chunk.
+    fn program_data(
+        &mut self,
+        addr: u32,
+        data: &[u8],
+        compressed: bool,
+        timeout: Duration,
+    ) -> Result<(), Error> {
+        let old_timeout = self.connection.timeout();
+        self.connection.set_timeout(timeout)?;
+        let result = self.program_chunks(addr, data, compressed);
+        self.connection.set_timeout(old_timeout)?;
+        result
+    }
 
+    fn program_chunks(&mut self, addr: u32, data: &[u8], compressed: bool) -> Result<(), Error> {
+        let pb = self.bar(data.len() as u64, "Programming");
+        let mut offset = 0;
+        let mut resumes = 0;
+        if self.pipeline {
+            let result =
+                self.eflash_loader()
+                    .flash_program_pipelined(addr, data, compressed, |size| {
+                        offset += size;
+                        pb.inc(size as u64);
+                    });
+            if let Err(e) = result {
+                if !is_link_error(&e) {
+                    return Err(e);
+                }
+                // a lost response shifts the ones after it, so an acknowledgement
+                // doesn't say which chunk made it. Resume from the first window the
+                // flash disagrees with, rewriting the same data is harmless.
+                offset = if compressed {
+                    0
+                } else {
+                    self.verified_prefix(addr, &data[..offset])
+                        .unwrap_or_else(|e| {
+                            log::debug!("Verifying programmed windows failed: {}", e);
+                            0
+                        })
+                };
+                log::warn!(
+                    "Pipelined program failed: {}, using lock-step from {:x}",
+                    e,
+                    addr + offset as u32
+                );
+                pb.set_position(offset as u64);
+                self.pipeline = false;
+            }
+        }
+        while offset < data.len() {
+            let end = (offset + PROGRAM_CHUNK_SIZE).min(data.len());
+            let chunk = &data[offset..end];
+            let chunk_addr = addr + offset as u32;
+            match self.program_chunk(chunk_addr, chunk, compressed) {
+                Ok(()) => {
+                    offset = end;
+                    pb.inc(chunk.len() as u64);
+                }
+                Err(e) if is_link_error(&e) && resumes < MAX_RESUMES => {
+                    resumes += 1;
+                    log::warn!(
+                        "Connection lost at {:x}: {}, resuming ({}/{})",
+                        chunk_addr,
+                        e,
+                        resumes,
+                        MAX_RESUMES
+                    );
+                    self.reconnect()?;
+                    if compressed {
+                        offset = 0;
+                        pb.set_position(0);
+                    }
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        self.finish(&pb);
         Ok(())
     }
 
+    /// Length of the leading part of `data` found in flash at `addr`, checked window by window
+    fn verified_prefix(&mut self, addr: u32, data: &[u8]) -> Result<usize, Error> {
+        const WINDOW: usize = PROGRAM_PIPELINE_DEPTH * PROGRAM_CHUNK_SIZE;
+        for (i, window) in data.chunks(WINDOW).enumerate() {
+            let offset = i * WINDOW;
+            let sha256 = self.sha256_read(addr + offset as u32, window.len() as u32)?;
+            if sha256 != Sha256::digest(window)[..] {
+                return Ok(offset);
+            }
+        }
+        Ok(data.len())
+    }
+
+    fn program_chunk(&mut self, addr: u32, data: &[u8], compressed: bool) -> Result<(), Error> {
+        let mut attempt = 1;
+        loop {
+            let result = if compressed {
+                self.eflash_loader().flash_decompress_write(addr, data)
+            } else {
+                self.eflash_loader().flash_program(addr, data)
+            };
+            match result {
+                Err(e) if is_link_error(&e) && attempt < PROGRAM_RETRIES => {
+                    log::debug!("Program {:x} failed: {}, retry {}", addr, e, attempt);
+                    attempt += 1;
+                    self.connection.drain()?;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Get back into the eflash_loader after the link dropped,
+    /// reloading it if the chip fell back to the boot ROM.
+    fn reconnect(&mut self) -> Result<(), Error> {
+        self.connection.drain()?;
+        if self.step_down_baud() {
+            self.connection.set_baud(self.flash_speed)?;
+        }
+        if self.handshake().is_ok() && self.eflash_loader().flash_read(0, 1).is_ok() {
+            return Ok(());
+        }
+        log::info!("eflash_loader lost, reloading");
+        self.connection.set_baud(self.initial_speed)?;
+        self.start_connection()?;
+        self.load_eflash_loader()
+    }
+
     pub fn reset(&mut self) -> Result<(), Error> {
-        Ok(self.connection.reset()?)
+        self.connection.reset()
     }
 
-    fn boot_rom(&mut self) -> BootRom {
+    fn boot_rom(&mut self) -> BootRom<'_> {
         BootRom(&mut self.connection)
     }
 
-    fn eflash_loader(&mut self) -> EflashLoader {
+    fn eflash_loader(&mut self) -> EflashLoader<'_> {
         EflashLoader(&mut self.connection)
     }
 
@@ -246,6 +951,7 @@ impl Flasher {
 
     fn start_connection(&mut self) -> Result<(), Error> {
         log::info!("Start connection...");
+        self.loader_running = false;
         self.connection.reset_to_flash()?;
         for i in 1..=10 {
             self.connection.flush()?;
@@ -273,8 +979,8 @@ impl<'a> BootRom<'a> {
         Ok(())
     }
 
-    pub fn load_boot_header(&mut self, reader: &mut impl Read) -> Result<(), Error> {
-        let mut boot_header = vec![0u8; protocol::LOAD_BOOT_HEADER_LEN];
+    pub fn load_boot_header(&mut self, reader: &mut impl Read, len: usize) -> Result<(), Error> {
+        let mut boot_header = vec![0u8; len];
         reader.read_exact(&mut boot_header)?;
         self.0.command(protocol::LoadBootHeader { boot_header })?;
         Ok(())
@@ -312,15 +1018,9 @@ impl<'a> BootRom<'a> {
         Ok(size as u32)
     }
 
-    pub fn get_boot_info(&mut self, chip: ChipType) -> Result<protocol::BootInfoV2, Error> {
-        match chip {
-            // ChipType::BL602(_) => self.0.command(protocol::BootInfoReqV2 {}),
-            ChipType::BL602(_) => self
-                .0
-                .command(protocol::BootInfoReq {})
-                .map(|boot_info| boot_info.to_v2()),
-            ChipType::BL616(_) => self.0.command(protocol::BootInfoReqV2 {}),
-        }
+    pub fn get_boot_info(&mut self) -> Result<protocol::BootInfoV2, Error> {
+        let resp = self.0.command(protocol::BootInfoReq {})?;
+        protocol::BootInfoV2::from_resp(resp)
     }
 }
 
@@ -332,24 +1032,146 @@ impl<'a> EflashLoader<'a> {
     }
 
     pub fn flash_read(&mut self, addr: u32, size: u32) -> Result<Vec<u8>, Error> {
-        Ok(self.0.command(protocol::FlashRead { addr, size })?.data)
+        let resp = self.0.command(protocol::FlashRead { addr, size })?;
+        if resp.len as u32 != size {
+            return Err(Error::RespError);
+        }
+        Ok(resp.data)
     }
 
-    pub fn flash_program(&mut self, addr: u32, reader: &mut impl Read) -> Result<u32, Error> {
-        let mut data = vec![0u8; 4000];
-        let size = reader.read(&mut data)?;
-        if size == 0 {
-            return Ok(0);
+    pub fn flash_program(&mut self, addr: u32, data: &[u8]) -> Result<(), Error> {
+        self.0.command(protocol::FlashProgram {
+            addr,
+            data: data.to_vec(),
+        })?;
+
+        Ok(())
+    }
+
+    /// Write a chunk of an xz stream, `addr` is the segment address plus the offset in the stream
+    pub fn flash_decompress_write(&mut self, addr: u32, data: &[u8]) -> Result<(), Error> {
+        self.0.command(protocol::FlashDecompressWrite {
+            addr,
+            data: data.to_vec(),
+        })?;
+
+        Ok(())
+    }
+
+    /// Program `data` at `addr` in chunks with a few of them in flight,
+    /// calling `acked` with the size of every acknowledged chunk
+    pub fn flash_program_pipelined(
+        &mut self,
+        addr: u32,
+        data: &[u8],
+        compressed: bool,
+        mut acked: impl FnMut(usize),
+    ) -> Result<(), Error> {
+        let chunks = data.chunks(PROGRAM_CHUNK_SIZE);
+        let mut sizes = chunks.clone().map(|chunk| chunk.len());
+        let mut on_response = |_| {
+            acked(sizes.next().unwrap_or(0));
+            Ok(())
+        };
+        let addrs = (addr..).step_by(PROGRAM_CHUNK_SIZE);
+        if compressed {
+            let commands = addrs
+                .zip(chunks)
+                .map(|(addr, data)| protocol::FlashDecompressWrite {
+                    addr,
+                    data: data.to_vec(),
+                });
+            self.0
+                .pipeline(commands, PROGRAM_PIPELINE_DEPTH, &mut on_response)
+        } else {
+            let commands = addrs
+                .zip(chunks)
+                .map(|(addr, data)| protocol::FlashProgram {
+                    addr,
+                    data: data.to_vec(),
+                });
+            self.0
+                .pipeline(commands, PROGRAM_PIPELINE_DEPTH, &mut on_response)
         }
-        data.truncate(size);
+    }
 
-        self.0.command(protocol::FlashProgram { addr, data })?;
+    /// Read `range` in blocks with several requests in flight, handing each block to `f`.
+    ///
+    /// A block of the wrong size is a response paired with the wrong request,
+    /// and fails the read.
+    pub fn flash_read_pipelined(
+        &mut self,
+        range: Range<u32>,
+        mut f: impl FnMut(Vec<u8>) -> Result<(), Error>,
+    ) -> Result<(), Error> {
+        let commands =
+            range
+                .clone()
+                .step_by(READ_BLOCK_SIZE as usize)
+                .map(|addr| protocol::FlashRead {
+                    addr,
+                    size: (range.end - addr).min(READ_BLOCK_SIZE),
+                });
+        let mut sizes = commands.clone().map(|command| command.size as usize);
+        self.0.pipeline(commands, READ_PIPELINE_DEPTH, |resp| {
+            if Some(resp.len as usize) != sizes.next() {
+                return Err(Error::RespError);
+            }
+            f(resp.data)
+        })
+    }
 
-        Ok(size as u32)
+    pub fn flash_read_jedec_id(&mut self) -> Result<[u8; 3], Error> {
+        let data = self.0.command(protocol::FlashReadJid {})?.data;
+        let mut jedec_id = [0u8; 3];
+        jedec_id.copy_from_slice(data.get(0..3).ok_or(Error::RespError)?);
+        Ok(jedec_id)
+    }
+
+    pub fn mem_read(&mut self, addr: u32, len: u32) -> Result<Vec<u8>, Error> {
+        Ok(self.0.command(protocol::MemRead { addr, len })?.data)
+    }
+
+    pub fn mem_write(&mut self, addr: u32, value: u32) -> Result<(), Error> {
+        self.0.command(protocol::MemWrite { addr, value })?;
+
+        Ok(())
+    }
+
+    pub fn efuse_read(&mut self, addr: u32, len: u32) -> Result<Vec<u8>, Error> {
+        Ok(self.0.command(protocol::EfuseRead { addr, len })?.data)
+    }
+
+    pub fn efuse_write(&mut self, addr: u32, data: &[u8]) -> Result<(), Error> {
+        self.0.command(protocol::EfuseWrite {
+            addr,
+            data: data.to_vec(),
+        })?;
+
+        Ok(())
+    }
+
+    /// Erase `start..end`, waiting up to `deadline` while the loader reports it's busy
+    pub fn flash_erase(
+        &mut self,
+        start: u32,
+        end: u32,
+        deadline: Duration,
+        on_pending: impl FnMut(Duration),
+    ) -> Result<(), Error> {
+        self.0
+            .command_with_deadline(protocol::FlashErase { start, end }, deadline, on_pending)?;
+
+        Ok(())
     }
 
-    pub fn flash_erase(&mut self, start: u32, end: u32) -> Result<(), Error> {
-        self.0.command(protocol::FlashErase { start, end })?;
+    pub fn flash_chip_erase(
+        &mut self,
+        deadline: Duration,
+        on_pending: impl FnMut(Duration),
+    ) -> Result<(), Error> {
+        self.0
+            .command_with_deadline(protocol::FlashChipErase {}, deadline, on_pending)?;
 
         Ok(())
     }
@@ -357,9 +1179,10 @@ impl<'a> EflashLoader<'a> {
 
 mod protocol {
     use crate::connection::{Command, Response};
+    use crate::Error;
+    use byteorder::{ByteOrder, LittleEndian};
     use deku::prelude::*;
 
-    pub const LOAD_BOOT_HEADER_LEN: usize = 176;
     pub const LOAD_SEGMENT_HEADER_LEN: usize = 16;
 
     #[derive(Debug, DekuWrite, Default)]
@@ -372,38 +1195,44 @@ mod protocol {
 
     #[derive(Debug, DekuWrite, Default)]
     pub struct BootInfoReq {}
-    #[derive(Debug, DekuRead, Default)]
+    /// The reply is longer on newer chips, so it's read raw and decoded after
+    #[derive(Debug, DekuRead)]
     pub struct BootInfo {
         pub len: u16,
-        pub bootrom_version: u32,
-        pub otp_info: [u8; 16],
+        #[deku(count = "len")]
+        pub data: Vec<u8>,
     }
     impl_command!(0x10, BootInfoReq, BootInfo);
-    impl BootInfo {
-        pub fn to_v2(self) -> BootInfoV2 {
-            BootInfoV2 {
-                len: self.len,
-                bootrom_version: self.bootrom_version,
-                otp_info: self.otp_info,
-                unknow_info: [0; 4],
-            }
-        }
-    }
 
-    #[derive(Debug, DekuWrite, Default)]
-    pub struct BootInfoReqV2 {}
-    #[derive(Debug, DekuRead, Default)]
+    #[derive(Debug, Default)]
     pub struct BootInfoV2 {
         pub len: u16,
         pub bootrom_version: u32,
         pub otp_info: [u8; 16],
         pub unknow_info: [u8; 4], // bl616
     }
-    impl_command!(0x10, BootInfoReqV2, BootInfoV2);
+    impl BootInfoV2 {
+        pub fn from_resp(resp: BootInfo) -> Result<Self, Error> {
+            let data = &resp.data;
+            if data.len() < 20 {
+                return Err(Error::RespError);
+            }
+            let mut info = BootInfoV2 {
+                len: resp.len,
+                bootrom_version: LittleEndian::read_u32(&data[0..4]),
+                ..Default::default()
+            };
+            info.otp_info.copy_from_slice(&data[4..20]);
+            if let Some(unknow_info) = data.get(20..24) {
+                info.unknow_info.copy_from_slice(unknow_info);
+            }
+            Ok(info)
+        }
+    }
 
     #[derive(Debug, DekuWrite, Default)]
     pub struct LoadBootHeader {
-        // length must be 176
+        // the chip's boot header length, 176 on BL602
         pub boot_header: Vec<u8>,
     }
     impl_command!(0x11, LoadBootHeader);
@@ -441,6 +1270,13 @@ mod protocol {
     }
     impl_command!(0x31, FlashProgram);
 
+    #[derive(Debug, DekuWrite, Default)]
+    pub struct FlashDecompressWrite {
+        pub addr: u32,
+        pub data: Vec<u8>,
+    }
+    impl_command!(0x3f, FlashDecompressWrite);
+
     #[derive(Debug, DekuWrite, Default)]
     pub struct FlashRead {
         pub addr: u32,
@@ -454,6 +1290,20 @@ mod protocol {
     }
     impl_command!(0x32, FlashRead, FlashReadResp);
 
+    #[derive(Debug, DekuWrite, Default)]
+    pub struct FlashChipErase {}
+    impl_command!(0x3c, FlashChipErase);
+
+    #[derive(Debug, DekuWrite, Default)]
+    pub struct FlashReadJid {}
+    #[derive(Debug, DekuRead)]
+    pub struct FlashReadJidResp {
+        pub len: u16,
+        #[deku(count = "len")]
+        pub data: Vec<u8>,
+    }
+    impl_command!(0x36, FlashReadJid, FlashReadJidResp);
+
     #[derive(Debug, DekuWrite, Default)]
     pub struct Sha256Read {
         pub addr: u32,
@@ -466,4 +1316,44 @@ mod protocol {
         pub digest: [u8; 32],
     }
     impl_command!(0x3d, Sha256Read, Sha256ReadResp);
+
+    #[derive(Debug, DekuWrite, Default)]
+    pub struct EfuseWrite {
+        pub addr: u32,
+        pub data: Vec<u8>,
+    }
+    impl_command!(0x40, EfuseWrite);
+
+    #[derive(Debug, DekuWrite, Default)]
+    pub struct EfuseRead {
+        pub addr: u32,
+        pub len: u32,
+    }
+    #[derive(Debug, DekuRead)]
+    pub struct EfuseReadResp {
+        pub len: u16,
+        #[deku(count = "len")]
+        pub data: Vec<u8>,
+    }
+    impl_command!(0x41, EfuseRead, EfuseReadResp);
+
+    #[derive(Debug, DekuWrite, Default)]
+    pub struct MemWrite {
+        pub addr: u32,
+        pub value: u32,
+    }
+    impl_command!(0x50, MemWrite);
+
+    #[derive(Debug, DekuWrite, Default)]
+    pub struct MemRead {
+        pub addr: u32,
+        pub len: u32,
+    }
+    #[derive(Debug, DekuRead)]
+    pub struct MemReadResp {
+        pub len: u16,
+        #[deku(count = "len")]
+        pub data: Vec<u8>,
+    }
+    impl_command!(0x51, MemRead, MemReadResp);
 }
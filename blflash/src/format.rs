@@ -0,0 +1,237 @@
+//! Firmware file formats besides ELF and raw bin.
+//!
+//! Intel HEX, Motorola S-record and UF2 files carry their own addresses.
+//! Addresses inside the chip's flash XIP window are translated to flash
+//! offsets, anything else is taken as a flash offset already and has to fit
+//! the size of the window.
+
+use crate::{
+    chip::Chip,
+    elf::{merge_segments, CodeSegment, RomSegment},
+    Error,
+};
+use std::path::Path;
+
+const UF2_MAGIC_START0: u32 = 0x0A324655;
+const UF2_MAGIC_START1: u32 = 0x9E5D5157;
+const UF2_MAGIC_END: u32 = 0x0AB16F30;
+const UF2_BLOCK_SIZE: usize = 512;
+const UF2_FLAG_NOT_MAIN_FLASH: u32 = 0x1;
+const UF2_FLAG_FAMILY_ID: u32 = 0x2000;
+/// UF2 family ID registered for BL602 in utils/uf2families.json of
+/// https://github.com/microsoft/uf2, the other chips have none
+pub const UF2_FAMILY_BL602: u32 = 0xde1270b7;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Format {
+    Bin,
+    Elf,
+    IntelHex,
+    Srec,
+    Uf2,
+}
+
+impl Format {
+    /// The format named by the file extension, or sniffed from the content
+    pub fn detect(path: Option<&Path>, data: &[u8]) -> Format {
+        let extension = path
+            .and_then(Path::extension)
+            .and_then(|ext| ext.to_str())
+            .map(str::to_ascii_lowercase);
+        match extension.as_deref() {
+            Some("bin") => Format::Bin,
+            Some("elf") => Format::Elf,
+            Some("hex") | Some("ihex") | Some("ihx") => Format::IntelHex,
+            Some("srec") | Some("s19") | Some("s28") | Some("s37") | Some("mot") => Format::Srec,
+            Some("uf2") => Format::Uf2,
+            _ => Format::sniff(data),
+        }
+    }
+
+    fn sniff(data: &[u8]) -> Format {
+        match data {
+            [0x7f, b'E', b'L', b'F', ..] => Format::Elf,
+            _ if word(data, 0) == Some(UF2_MAGIC_START0)
+                && word(data, 4) == Some(UF2_MAGIC_START1) =>
+            {
+                Format::Uf2
+            }
+            [b':', ..] => Format::IntelHex,
+            [b'S', b'0'..=b'9', ..] => Format::Srec,
+            _ => Format::Bin,
+        }
+    }
+}
+
+fn word(data: &[u8], offset: usize) -> Option<u32> {
+    let bytes = data.get(offset..offset + 4)?;
+    Some(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+/// Non-empty lines of a text file, with their line numbers for errors
+fn records<'a>(
+    data: &'a [u8],
+    format: &'static str,
+) -> impl Iterator<Item = Result<(usize, &'a str), Error>> {
+    data.split(|&b| b == b'\n')
+        .enumerate()
+        .map(move |(i, line)| match std::str::from_utf8(line) {
+            Ok(line) => Ok((i + 1, line.trim())),
+            Err(_) => Err(Error::InvalidRecord {
+                format,
+                line: i + 1,
+            }),
+        })
+        .filter(|record| !matches!(record, Ok((_, ""))))
+}
+
+/// Data records of an Intel HEX file at their absolute addresses
+pub fn parse_ihex(data: &[u8]) -> Result<Vec<RomSegment<'static>>, Error> {
+    let mut segments = Vec::new();
+    let mut base = 0u32;
+    for record in records(data, "Intel HEX") {
+        let (line, record) = record?;
+        let invalid = || Error::InvalidRecord {
+            format: "Intel HEX",
+            line,
+        };
+        let bytes = record
+            .strip_prefix(':')
+            .and_then(|record| hex::decode(record).ok())
+            .ok_or_else(invalid)?;
+        if bytes.len() < 5
+            || bytes.len() != bytes[0] as usize + 5
+            || bytes.iter().fold(0u8, |sum, &b| sum.wrapping_add(b)) != 0
+        {
+            return Err(invalid());
+        }
+        let addr = u16::from_be_bytes([bytes[1], bytes[2]]) as u32;
+        let payload = &bytes[4..bytes.len() - 1];
+        match (bytes[3], payload) {
+            (0x00, _) => segments.push(RomSegment::from_vec(base + addr, payload.to_vec())),
+            (0x01, _) => break,
+            (0x02, &[hi, lo]) => base = (u16::from_be_bytes([hi, lo]) as u32) << 4,
+            (0x04, &[hi, lo]) => base = (u16::from_be_bytes([hi, lo]) as u32) << 16,
+            // start addresses
+            (0x03, _) | (0x05, _) => {}
+            _ => return Err(invalid()),
+        }
+    }
+    Ok(segments)
+}
+
+/// Data records of a Motorola S-record file at their addresses
+pub fn parse_srec(data: &[u8]) -> Result<Vec<RomSegment<'static>>, Error> {
+    let mut segments = Vec::new();
+    for record in records(data, "S-record") {
+        let (line, record) = record?;
+        let invalid = || Error::InvalidRecord {
+            format: "S-record",
+            line,
+        };
+        let kind = record
+            .strip_prefix('S')
+            .and_then(|record| record.chars().next())
+            .ok_or_else(invalid)?;
+        let bytes = record
+            .get(2..)
+            .and_then(|record| hex::decode(record).ok())
+            .ok_or_else(invalid)?;
+        if bytes.len() < 3
+            || bytes.len() != bytes[0] as usize + 1
+            || bytes.iter().fold(0u8, |sum, &b| sum.wrapping_add(b)) != 0xff
+        {
+            return Err(invalid());
+        }
+        let addr_len = match kind {
+            '1' => 2,
+            '2' => 3,
+            '3' => 4,
+            // header and record counts
+            '0' | '5' | '6' => continue,
+            // start addresses end the data
+            '7' | '8' | '9' => break,
+            _ => return Err(invalid()),
+        };
+        let body = &bytes[1..bytes.len() - 1];
+        if body.len() < addr_len {
+            return Err(invalid());
+        }
+        let addr = body[..addr_len]
+            .iter()
+            .fold(0u32, |addr, &b| addr << 8 | b as u32);
+        segments.push(RomSegment::from_vec(addr, body[addr_len..].to_vec()));
+    }
+    Ok(segments)
+}
+
+/// Main flash blocks of a UF2 file, skipping those tagged with a family other than `family`
+pub fn parse_uf2(data: &[u8], family: Option<u32>) -> Result<Vec<RomSegment<'static>>, Error> {
+    let mut segments = Vec::new();
+    for (i, block) in data.chunks(UF2_BLOCK_SIZE).enumerate() {
+        let invalid = || Error::InvalidRecord {
+            format: "UF2",
+            line: i + 1,
+        };
+        let field = |n: usize| word(block, n * 4).ok_or_else(invalid);
+        if block.len() != UF2_BLOCK_SIZE
+            || field(0)? != UF2_MAGIC_START0
+            || field(1)? != UF2_MAGIC_START1
+            || word(block, UF2_BLOCK_SIZE - 4) != Some(UF2_MAGIC_END)
+        {
+            return Err(invalid());
+        }
+        let (flags, addr, size, block_family) =
+            (field(2)?, field(3)?, field(4)? as usize, field(7)?);
+        if size > 476 {
+            return Err(invalid());
+        }
+        if flags & UF2_FLAG_NOT_MAIN_FLASH != 0
+            || (flags & UF2_FLAG_FAMILY_ID != 0 && Some(block_family) != family)
+        {
+            continue;
+        }
+        segments.push(RomSegment::from_vec(addr, block[32..32 + size].to_vec()));
+    }
+    Ok(segments)
+}
+
+/// Segments of a HEX, S-record or UF2 file at their flash offsets, joined where contiguous
+pub fn read_segments(
+    chip: &dyn Chip,
+    format: Format,
+    data: &[u8],
+) -> Result<Vec<RomSegment<'static>>, Error> {
+    let segments = match format {
+        Format::IntelHex => parse_ihex(data)?,
+        Format::Srec => parse_srec(data)?,
+        Format::Uf2 => parse_uf2(data, chip.uf2_family_id())?,
+        Format::Bin | Format::Elf => return Err(Error::ArgsError),
+    };
+    let segments: Vec<_> = segments
+        .into_iter()
+        .map(|segment| {
+            let addr = chip
+                .get_flash_segment(CodeSegment::from_slice(segment.addr, &segment.data))
+                .map_or(segment.addr, |flash| flash.addr);
+            RomSegment { addr, ..segment }
+        })
+        .collect();
+    if segments.is_empty() {
+        return Err(Error::EmptyImage);
+    }
+    // an address that is neither in the XIP window nor a flash offset would
+    // blow the bin up to span it
+    let flash_size = chip.flash_window_size();
+    if let Some(segment) = segments
+        .iter()
+        .find(|segment| segment.addr as u64 + segment.size() as u64 > flash_size as u64)
+    {
+        return Err(Error::SegmentExceedsFlash {
+            addr: segment.addr,
+            size: segment.size(),
+            flash_size,
+        });
+    }
+    Ok(merge_segments(segments))
+}
@@ -0,0 +1,55 @@
+//! Splitting the host's byte stream back into what it sent.
+
+use byteorder::{ByteOrder, LittleEndian};
+use std::time::{Duration, Instant};
+
+const HANDSHAKE_BYTE: u8 = 0x55;
+/// Silence that separates two handshakes
+const HANDSHAKE_GAP: Duration = Duration::from_millis(20);
+
+/// What the host sent: a handshake burst or a complete command frame
+pub(crate) enum Incoming {
+    Handshake,
+    Frame(Vec<u8>),
+}
+
+/// Splits the host's byte stream into handshakes and command frames
+#[derive(Default)]
+pub(crate) struct Framer {
+    input: Vec<u8>,
+    last_handshake: Option<Instant>,
+}
+
+impl Framer {
+    pub(crate) fn push(&mut self, data: &[u8]) -> Vec<Incoming> {
+        self.input.extend_from_slice(data);
+        let mut incoming = Vec::new();
+        loop {
+            if self.input.first() == Some(&HANDSHAKE_BYTE) {
+                let run = self
+                    .input
+                    .iter()
+                    .take_while(|&&b| b == HANDSHAKE_BYTE)
+                    .count();
+                self.input.drain(..run);
+                if self
+                    .last_handshake
+                    .map_or(true, |last| last.elapsed() > HANDSHAKE_GAP)
+                {
+                    incoming.push(Incoming::Handshake);
+                }
+                self.last_handshake = Some(Instant::now());
+                continue;
+            }
+            if self.input.len() < 4 {
+                return incoming;
+            }
+            let len = LittleEndian::read_u16(&self.input[2..4]) as usize;
+            if self.input.len() < 4 + len {
+                return incoming;
+            }
+            self.last_handshake = None;
+            incoming.push(Incoming::Frame(self.input.drain(..4 + len).collect()));
+        }
+    }
+}
@@ -1,9 +1,40 @@
-use crate::Error;
+use crate::{elf::RomSegment, flasher::FlashTiming, Error};
 use byteorder::{NativeEndian, ReadBytesExt};
 use deku::prelude::*;
 use serde::Deserialize;
 use sha2::{Digest, Sha256};
 use std::io::Cursor;
+use std::time::Duration;
+
+pub const BOOT_HEADER_LEN: usize = 176;
+/// Offset of `img_len`, the image length or segment count
+pub const IMG_LEN_OFFSET: usize = 120;
+
+/// A chip's boot header, filled in from its efuse_bootheader_cfg.conf
+pub trait BootHeader {
+    /// The header padded to `offset`, followed by `image`
+    fn make_image(&mut self, offset: usize, image: Vec<u8>) -> Result<Vec<u8>, Error>;
+    /// Boot header followed by a header and the data of each segment, as the boot ROM loads them
+    fn make_ram_image(&mut self, entry: u32, segments: &[RomSegment]) -> Result<Vec<u8>, Error>;
+    /// Flash timing, used for erase and program timeouts
+    fn flash_timing(&self) -> FlashTiming;
+}
+
+/// Every segment behind a 16-byte header of its address, size and crc
+pub(super) fn segment_area(segments: &[RomSegment]) -> Vec<u8> {
+    let mut image = Vec::new();
+    for segment in segments {
+        let mut header = Vec::with_capacity(16);
+        header.extend(&segment.addr.to_le_bytes());
+        header.extend(&segment.size().to_le_bytes());
+        header.extend(&[0u8; 4]);
+        let crc = crc::crc32::checksum_ieee(&header);
+        header.extend(&crc.to_le_bytes());
+        image.append(&mut header);
+        image.extend_from_slice(&segment.data);
+    }
+    image
+}
 
 #[derive(Debug, Deserialize, Default, Clone)]
 pub struct BootHeaderCfgFile {
@@ -211,6 +242,18 @@ pub struct BootHeaderCfg {
 }
 
 impl FlashCfg {
+    /// Worst-case operation times, the config stores them in milliseconds
+    pub fn timing(&self) -> FlashTiming {
+        let ms = |time: u16| Duration::from_millis(time as u64);
+        FlashTiming {
+            sector_erase: ms(self.sector_erase_time),
+            blk32k_erase: ms(self.blk32k_erase_time),
+            blk64k_erase: ms(self.blk64k_erase_time),
+            chip_erase: ms(self.chip_erase_time),
+            page_program: ms(self.page_prog_time),
+            page_size: self.page_size as u32,
+        }
+    }
     fn checksum(&self) -> u32 {
         let data = self.to_bytes().unwrap();
         crc::crc32::checksum_ieee(&data[4..data.len() - 4])
@@ -241,8 +284,11 @@ impl BootHeaderCfg {
         self.boot_cfg.hash_7 = reader.read_u32::<NativeEndian>()?;
         Ok(())
     }
-    pub fn make_image(&mut self, offset: usize, mut image: Vec<u8>) -> Result<Vec<u8>, Error> {
-        let binlen = ((image.len() + 15) / 16) * 16;
+}
+
+impl BootHeader for BootHeaderCfg {
+    fn make_image(&mut self, offset: usize, mut image: Vec<u8>) -> Result<Vec<u8>, Error> {
+        let binlen = image.len().div_ceil(16) * 16;
         image.resize(binlen, 0xFF);
         let hash = Sha256::digest(&image);
         self.update_sha256(&hash[..])?;
@@ -258,4 +304,24 @@ impl BootHeaderCfg {
 
         Ok(header)
     }
+    fn make_ram_image(&mut self, entry: u32, segments: &[RomSegment]) -> Result<Vec<u8>, Error> {
+        let mut image = segment_area(segments);
+        let hash = Sha256::digest(&image);
+        self.update_sha256(&hash[..])?;
+        self.boot_cfg.no_segment = 0;
+        self.boot_cfg.img_len = segments.len() as u32;
+        self.boot_cfg.bootentry = entry;
+        self.boot_cfg.img_start = entry;
+        self.flash_cfg.update()?;
+        self.clk_cfg.update()?;
+        self.update()?;
+
+        let mut header = self.to_bytes()?;
+        header.append(&mut image);
+
+        Ok(header)
+    }
+    fn flash_timing(&self) -> FlashTiming {
+        self.flash_cfg.timing()
+    }
 }
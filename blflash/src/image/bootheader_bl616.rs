@@ -0,0 +1,275 @@
+use super::bootheader::{BootHeader, FlashCfg};
+use crate::{elf::RomSegment, flasher::FlashTiming, Error};
+use byteorder::{NativeEndian, ReadBytesExt};
+use deku::prelude::*;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::io::Cursor;
+
+pub const BL616_BOOT_HEADER_LEN: usize = 256;
+/// Offset of `img_len_cnt`, the image length or segment count
+pub const BL616_IMG_LEN_OFFSET: usize = 132;
+
+#[derive(Debug, Deserialize, Default, Clone)]
+pub struct Bl616BootHeaderCfgFile {
+    #[serde(rename = "BOOTHEADER_GROUP0_CFG")]
+    pub boot_header_cfg: Bl616BootHeaderCfg,
+}
+
+#[derive(Debug, Deserialize, DekuWrite, Default, Clone)]
+pub struct Bl616ClkCfg {
+    // 100
+    clkcfg_magic_code: u32,
+    // 104
+    xtal_type: u8,
+    mcu_clk: u8,
+    mcu_clk_div: u8,
+    mcu_bclk_div: u8,
+    // 108
+    mcu_pbclk_div: u8,
+    emi_clk: u8,
+    emi_clk_div: u8,
+    flash_clk_type: u8,
+    // 112
+    flash_clk_div: u8,
+    wifipll_pu: u8,
+    aupll_pu: u8,
+    #[serde(skip)]
+    _unused1: u8,
+    // 116
+    #[deku(update = "self.checksum()")]
+    clkcfg_crc32: u32,
+}
+
+/// The flags are packed into `flags` by hand, see `Bl616BootCfg::flags`
+#[derive(Debug, Deserialize, DekuWrite, Default, Clone)]
+pub struct Bl616BootCfg {
+    // 120
+    #[serde(skip)]
+    #[deku(update = "self.flags()")]
+    flags: u32,
+    #[deku(skip)]
+    sign: u8,
+    #[deku(skip)]
+    encrypt_type: u8,
+    #[deku(skip)]
+    key_sel: u8,
+    #[deku(skip)]
+    xts_mode: u8,
+    #[deku(skip)]
+    aes_region_lock: u8,
+    #[deku(skip)]
+    pub no_segment: u8,
+    #[deku(skip)]
+    boot2_enable: u8,
+    #[deku(skip)]
+    boot2_rollback: u8,
+    #[deku(skip)]
+    pub(super) cpu_master_id: u8,
+    #[deku(skip)]
+    notload_in_bootrom: u8,
+    #[deku(skip)]
+    crc_ignore: u8,
+    #[deku(skip)]
+    hash_ignore: u8,
+    #[deku(skip)]
+    power_on_mm: u8,
+    #[deku(skip)]
+    em_sel: u8,
+    #[deku(skip)]
+    cmds_en: u8,
+    #[deku(skip)]
+    cmds_wrap_mode: u8,
+    #[deku(skip)]
+    cmds_wrap_len: u8,
+    #[deku(skip)]
+    icache_invalid: u8,
+    #[deku(skip)]
+    dcache_invalid: u8,
+
+    // 124
+    pub(super) group_image_offset: u32,
+    // 128
+    aes_region_len: u32,
+    // 132
+    pub img_len_cnt: u32,
+    // 136
+    hash_0: u32,
+    hash_1: u32,
+    hash_2: u32,
+    hash_3: u32,
+    hash_4: u32,
+    hash_5: u32,
+    hash_6: u32,
+    hash_7: u32,
+}
+
+/// Settings of the one CPU the BL616 boot ROM starts
+#[derive(Debug, Deserialize, DekuWrite, Default, Clone)]
+pub struct Bl616CpuCfg {
+    // 168
+    pub(super) config_enable: u8,
+    halt_cpu: u8,
+    #[serde(skip)]
+    #[deku(update = "self.cache_flags()")]
+    cache_flags: u8,
+    #[serde(skip)]
+    _unused1: u8,
+    #[deku(skip)]
+    cache_enable: u8,
+    #[deku(skip)]
+    cache_wa: u8,
+    #[deku(skip)]
+    cache_wb: u8,
+    #[deku(skip)]
+    cache_wt: u8,
+    #[deku(skip)]
+    cache_way_dis: u8,
+    // 172
+    pub(super) image_address_offset: u32,
+    // 176
+    pub(super) boot_entry: u32,
+    // 180
+    msp_val: u32,
+}
+
+#[derive(Debug, Deserialize, DekuWrite, Default, Clone)]
+pub struct Bl616BootHeaderCfg {
+    magic_code: u32,
+    revision: u32,
+
+    // 8
+    #[serde(flatten)]
+    pub flash_cfg: FlashCfg,
+
+    #[serde(flatten)]
+    pub clk_cfg: Bl616ClkCfg,
+
+    #[serde(flatten)]
+    pub boot_cfg: Bl616BootCfg,
+
+    #[serde(flatten)]
+    pub cpu_cfg: Bl616CpuCfg,
+
+    // 184
+    boot2_pt_table_0: u32,
+    boot2_pt_table_1: u32,
+    // 192
+    flash_cfg_table_addr: u32,
+    flash_cfg_table_len: u32,
+    // 200, (addr, value) pairs applied on flash read and before the jump
+    #[serde(skip)]
+    _patches: [u32; 12],
+    #[serde(skip)]
+    _unused1: u32,
+
+    // 252
+    #[deku(update = "self.checksum()")]
+    crc32: u32,
+}
+
+impl Bl616ClkCfg {
+    fn checksum(&self) -> u32 {
+        let data = self.to_bytes().unwrap();
+        crc::crc32::checksum_ieee(&data[4..data.len() - 4])
+    }
+}
+
+impl Bl616BootCfg {
+    /// Bit positions as in the SDK's `boot_flag_config` word
+    fn flags(&self) -> u32 {
+        let field = |value: u8, pos: u32, bits: u32| (value as u32 & ((1 << bits) - 1)) << pos;
+        field(self.sign, 0, 2)
+            | field(self.encrypt_type, 2, 2)
+            | field(self.key_sel, 4, 2)
+            | field(self.xts_mode, 6, 1)
+            | field(self.aes_region_lock, 7, 1)
+            | field(self.no_segment, 8, 1)
+            | field(self.boot2_enable, 9, 1)
+            | field(self.boot2_rollback, 10, 1)
+            | field(self.cpu_master_id, 11, 4)
+            | field(self.notload_in_bootrom, 15, 1)
+            | field(self.crc_ignore, 16, 1)
+            | field(self.hash_ignore, 17, 1)
+            | field(self.power_on_mm, 18, 1)
+            | field(self.em_sel, 19, 3)
+            | field(self.cmds_en, 22, 1)
+            | field(self.cmds_wrap_mode, 23, 2)
+            | field(self.cmds_wrap_len, 25, 4)
+            | field(self.icache_invalid, 29, 1)
+            | field(self.dcache_invalid, 30, 1)
+    }
+    pub(super) fn set_hash(&mut self, hash: &[u8]) -> Result<(), Error> {
+        let mut reader = Cursor::new(hash);
+        self.hash_0 = reader.read_u32::<NativeEndian>()?;
+        self.hash_1 = reader.read_u32::<NativeEndian>()?;
+        self.hash_2 = reader.read_u32::<NativeEndian>()?;
+        self.hash_3 = reader.read_u32::<NativeEndian>()?;
+        self.hash_4 = reader.read_u32::<NativeEndian>()?;
+        self.hash_5 = reader.read_u32::<NativeEndian>()?;
+        self.hash_6 = reader.read_u32::<NativeEndian>()?;
+        self.hash_7 = reader.read_u32::<NativeEndian>()?;
+        Ok(())
+    }
+}
+
+impl Bl616CpuCfg {
+    fn cache_flags(&self) -> u8 {
+        (self.cache_enable & 1)
+            | (self.cache_wa & 1) << 1
+            | (self.cache_wb & 1) << 2
+            | (self.cache_wt & 1) << 3
+            | (self.cache_way_dis & 0xf) << 4
+    }
+}
+
+impl Bl616BootHeaderCfg {
+    fn checksum(&self) -> u32 {
+        let data = self.to_bytes().unwrap();
+        crc::crc32::checksum_ieee(&data[0..data.len() - 4])
+    }
+    fn header_bytes(&mut self) -> Result<Vec<u8>, Error> {
+        self.flash_cfg.update()?;
+        self.clk_cfg.update()?;
+        self.boot_cfg.update()?;
+        self.cpu_cfg.update()?;
+        self.update()?;
+        Ok(self.to_bytes()?)
+    }
+}
+
+impl BootHeader for Bl616BootHeaderCfg {
+    fn make_image(&mut self, offset: usize, mut image: Vec<u8>) -> Result<Vec<u8>, Error> {
+        let binlen = image.len().div_ceil(16) * 16;
+        image.resize(binlen, 0xFF);
+        let hash = Sha256::digest(&image);
+        self.boot_cfg.set_hash(&hash[..])?;
+        self.boot_cfg.img_len_cnt = image.len() as u32;
+        self.boot_cfg.group_image_offset = offset as u32;
+
+        let mut header = self.header_bytes()?;
+        header.resize(offset, 0xff);
+        header.append(&mut image);
+
+        Ok(header)
+    }
+
+    fn make_ram_image(&mut self, entry: u32, segments: &[RomSegment]) -> Result<Vec<u8>, Error> {
+        let image = super::bootheader::segment_area(segments);
+        let hash = Sha256::digest(&image);
+        self.boot_cfg.set_hash(&hash[..])?;
+        self.boot_cfg.no_segment = 0;
+        self.boot_cfg.img_len_cnt = segments.len() as u32;
+        self.cpu_cfg.config_enable = 1;
+        self.cpu_cfg.boot_entry = entry;
+
+        let mut header = self.header_bytes()?;
+        header.extend(image);
+
+        Ok(header)
+    }
+
+    fn flash_timing(&self) -> FlashTiming {
+        self.flash_cfg.timing()
+    }
+}
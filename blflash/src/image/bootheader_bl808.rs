@@ -0,0 +1,179 @@
+use super::bootheader::{BootHeader, FlashCfg};
+use super::bootheader_bl616::{Bl616BootCfg, Bl616CpuCfg};
+use crate::{elf::RomSegment, flasher::FlashTiming, Error};
+use deku::prelude::*;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+
+pub const BL808_BOOT_HEADER_LEN: usize = 352;
+/// Offset of `img_len_cnt`, the image length or segment count
+pub const BL808_IMG_LEN_OFFSET: usize = 140;
+/// Cores with a CPU config in the boot header, in header order
+pub const BL808_CORES: [&str; 3] = ["m0", "d0", "lp"];
+
+#[derive(Debug, Deserialize, Default, Clone)]
+pub struct Bl808BootHeaderCfgFile {
+    #[serde(rename = "BOOTHEADER_GROUP0_CFG")]
+    pub boot_header_cfg: Bl808BootHeaderCfg,
+}
+
+#[derive(Debug, Deserialize, DekuWrite, Default, Clone)]
+pub struct Bl808ClkCfg {
+    // 100
+    clkcfg_magic_code: u32,
+    // 104
+    xtal_type: u8,
+    mcu_clk: u8,
+    mcu_clk_div: u8,
+    mcu_bclk_div: u8,
+    // 108
+    mcu_pbclk_div: u8,
+    lp_div: u8,
+    dsp_clk: u8,
+    dsp_clk_div: u8,
+    // 112
+    dsp_bclk_div: u8,
+    dsp_pbclk: u8,
+    dsp_pbclk_div: u8,
+    emi_clk: u8,
+    // 116
+    emi_clk_div: u8,
+    flash_clk_type: u8,
+    flash_clk_div: u8,
+    wifipll_pu: u8,
+    // 120
+    aupll_pu: u8,
+    cpupll_pu: u8,
+    mipipll_pu: u8,
+    uhspll_pu: u8,
+    // 124
+    #[deku(update = "self.checksum()")]
+    clkcfg_crc32: u32,
+}
+
+/// The basic and CPU configs are laid out as on BL616, with one CPU config per core
+#[derive(Debug, Deserialize, DekuWrite, Default, Clone)]
+pub struct Bl808BootHeaderCfg {
+    magic_code: u32,
+    revision: u32,
+
+    // 8
+    #[serde(flatten)]
+    pub flash_cfg: FlashCfg,
+
+    #[serde(flatten)]
+    pub clk_cfg: Bl808ClkCfg,
+
+    // 128
+    #[serde(flatten)]
+    pub boot_cfg: Bl616BootCfg,
+
+    // 176, 192 and 208, the `[BOOTHEADER_GROUP0_CFG.m0]` tables
+    pub m0: Bl616CpuCfg,
+    pub d0: Bl616CpuCfg,
+    pub lp: Bl616CpuCfg,
+
+    // 224
+    boot2_pt_table_0: u32,
+    boot2_pt_table_1: u32,
+    // 232
+    flash_cfg_table_addr: u32,
+    flash_cfg_table_len: u32,
+    // 240, (addr, value) pairs applied on flash read and before the jump
+    #[serde(skip)]
+    _patches: [u32; 16],
+    #[serde(skip)]
+    _unused1: [u32; 11],
+
+    // 348
+    #[deku(update = "self.checksum()")]
+    crc32: u32,
+}
+
+impl Bl808ClkCfg {
+    fn checksum(&self) -> u32 {
+        let data = self.to_bytes().unwrap();
+        crc::crc32::checksum_ieee(&data[4..data.len() - 4])
+    }
+}
+
+impl Bl808BootHeaderCfg {
+    fn checksum(&self) -> u32 {
+        let data = self.to_bytes().unwrap();
+        crc::crc32::checksum_ieee(&data[0..data.len() - 4])
+    }
+    fn cpu_cfg(&mut self, core: &str) -> Result<&mut Bl616CpuCfg, Error> {
+        match core {
+            "m0" => Ok(&mut self.m0),
+            "d0" => Ok(&mut self.d0),
+            "lp" => Ok(&mut self.lp),
+            _ => Err(Error::UnsupportedCore(core.to_string())),
+        }
+    }
+    fn header_bytes(&mut self) -> Result<Vec<u8>, Error> {
+        self.flash_cfg.update()?;
+        self.clk_cfg.update()?;
+        self.boot_cfg.update()?;
+        self.m0.update()?;
+        self.d0.update()?;
+        self.lp.update()?;
+        self.update()?;
+        Ok(self.to_bytes()?)
+    }
+
+    /// Header of one boot group, with `images` as (core, offset from the header, image).
+    ///
+    /// Every listed core is started with its flash window at its image. The
+    /// first image is the one the boot ROM checks, and its core is the master.
+    pub fn make_group_header(&mut self, images: &[(&str, u32, &[u8])]) -> Result<Vec<u8>, Error> {
+        let &(master, offset, image) = images.first().ok_or(Error::ArgsError)?;
+        for core in BL808_CORES.iter() {
+            self.cpu_cfg(core)?.config_enable = 0;
+        }
+        for &(core, offset, _) in images {
+            let cpu_cfg = self.cpu_cfg(core)?;
+            cpu_cfg.config_enable = 1;
+            cpu_cfg.image_address_offset = offset;
+        }
+
+        let hash = Sha256::digest(image);
+        self.boot_cfg.set_hash(&hash[..])?;
+        self.boot_cfg.img_len_cnt = image.len() as u32;
+        self.boot_cfg.group_image_offset = offset;
+        self.boot_cfg.cpu_master_id = BL808_CORES.iter().position(|&c| c == master).unwrap() as u8;
+
+        self.header_bytes()
+    }
+}
+
+impl BootHeader for Bl808BootHeaderCfg {
+    fn make_image(&mut self, offset: usize, mut image: Vec<u8>) -> Result<Vec<u8>, Error> {
+        let binlen = image.len().div_ceil(16) * 16;
+        image.resize(binlen, 0xFF);
+
+        let mut header = self.make_group_header(&[("m0", offset as u32, &image)])?;
+        header.resize(offset, 0xff);
+        header.append(&mut image);
+
+        Ok(header)
+    }
+
+    fn make_ram_image(&mut self, entry: u32, segments: &[RomSegment]) -> Result<Vec<u8>, Error> {
+        let image = super::bootheader::segment_area(segments);
+        let hash = Sha256::digest(&image);
+        self.boot_cfg.set_hash(&hash[..])?;
+        self.boot_cfg.no_segment = 0;
+        self.boot_cfg.img_len_cnt = segments.len() as u32;
+        self.m0.config_enable = 1;
+        self.m0.boot_entry = entry;
+
+        let mut header = self.header_bytes()?;
+        header.extend(image);
+
+        Ok(header)
+    }
+
+    fn flash_timing(&self) -> FlashTiming {
+        self.flash_cfg.timing()
+    }
+}
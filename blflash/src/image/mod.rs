@@ -1,5 +1,16 @@
 mod bootheader;
+mod bootheader_bl616;
+mod bootheader_bl808;
 mod partition;
 
-pub use bootheader::{BootHeaderCfg, BootHeaderCfgFile};
+pub use bootheader::{
+    BootHeader, BootHeaderCfg, BootHeaderCfgFile, BOOT_HEADER_LEN, IMG_LEN_OFFSET,
+};
+pub use bootheader_bl616::{
+    Bl616BootHeaderCfg, Bl616BootHeaderCfgFile, BL616_BOOT_HEADER_LEN, BL616_IMG_LEN_OFFSET,
+};
+pub use bootheader_bl808::{
+    Bl808BootHeaderCfg, Bl808BootHeaderCfgFile, BL808_BOOT_HEADER_LEN, BL808_CORES,
+    BL808_IMG_LEN_OFFSET,
+};
 pub use partition::PartitionCfg;
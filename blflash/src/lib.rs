@@ -1,49 +1,96 @@
+// deku's `DekuRead` derive expands to a hand-written div_ceil
+#![allow(clippy::manual_div_ceil)]
+
 pub mod chip;
 mod connection;
+pub mod detect;
+pub mod efuse;
 pub mod elf;
+#[cfg(feature = "emulator")]
+pub mod emulator;
 mod error;
 mod flasher;
+pub mod format;
+mod framing;
 pub mod image;
+pub mod multi;
+pub mod reset;
+pub mod trace;
+pub mod transport;
 
 pub use error::{Error, RomError};
-pub use flasher::Flasher;
+pub use flasher::{FlashInfo, FlashTiming, Flasher};
 
 use crate::{
-    chip::{Chip, ChipType},
-    elf::{FirmwareImage, RomSegment},
-    image::BootHeaderCfgFile,
+    chip::{Chip, ChipSelect},
+    elf::{segments_to_bin, FirmwareImage, RomSegment},
+    format::Format,
+    image::PartitionCfg,
+    reset::ResetConfig,
+    trace::{ReplayTransport, TraceRecorder},
+    transport::{TcpTransport, Transport},
 };
 use serial::{BaudRate, CharSize, FlowControl, Parity, SerialPort, SerialPortSettings, StopBits};
 use std::{
     borrow::Cow,
     fs::{read, File},
-    path::PathBuf,
+    ops::Range,
+    path::{Path, PathBuf},
 };
 use structopt::StructOpt;
 
-#[derive(StructOpt)]
+#[derive(StructOpt, Clone)]
 pub struct Connection {
-    /// Serial port
+    /// Serial port, tcp://host:port for a raw TCP serial server like ser2net,
+    /// or replay://trace.txt to play back a recorded trace.
+    /// Detected from the known USB-UART adapters if not given
     #[structopt(short, long)]
-    pub port: String,
+    pub port: Option<String>,
     /// Flash baud rate
     #[structopt(short, long, default_value = "1000000")]
     pub baud_rate: usize,
     /// Initial baud rate
     #[structopt(long, default_value = "115200")]
     pub initial_baud_rate: usize,
-    /// Reset pin
-    #[structopt(long, default_value = "rts")]
-    pub reset_pin: String,
-    /// boot pin
-    #[structopt(long, default_value = "!dtr")]
-    pub boot_pin: String,
-    /// chip type
+    /// Reset pin: rts, dtr or null, `!` inverts it [default: rts]
+    #[structopt(long)]
+    pub reset_pin: Option<String>,
+    /// boot pin [default: !dtr]
+    #[structopt(long)]
+    pub boot_pin: Option<String>,
+    /// Board preset for the pin wiring and timing:
+    /// default, pinecone, sipeed-m0, sipeed-m1s or ai-thinker
+    #[structopt(long, default_value = "default")]
+    pub board: String,
+    /// Steps entering download mode, like "boot=1,reset=1,wait 50,reset=0,wait 100,boot=0"
+    #[structopt(long)]
+    pub flash_sequence: Option<String>,
+    /// Steps resetting into the firmware
+    #[structopt(long)]
+    pub run_sequence: Option<String>,
+    /// Chip type: bl602, bl616, bl702 or bl808. auto picks it from the boot ROM,
+    /// which only works when the answer is unambiguous: BL602 and BL70x answer
+    /// alike and BL808 like BL616
     #[structopt(long, parse(try_from_str), default_value = "bl602")]
-    pub chip: ChipType,
+    pub chip: ChipSelect,
+    /// Record every command and response to this file
+    #[structopt(long, parse(from_os_str))]
+    pub trace: Option<PathBuf>,
+    /// Wait for every response before sending the next command
+    #[structopt(long)]
+    pub no_pipeline: bool,
+    /// Abort when the chip rejects a command checksum instead of resending it,
+    /// for adapters that corrupt data. Only has an effect on chips that check
+    /// checksums, this doesn't turn checking on
+    #[structopt(long)]
+    pub fatal_checksum_errors: bool,
+    /// eflash_loader RAM image to load instead of the bundled one,
+    /// required for chips without a bundled loader
+    #[structopt(long, parse(from_os_str))]
+    pub eflash_loader: Option<PathBuf>,
 }
 
-#[derive(StructOpt)]
+#[derive(StructOpt, Clone)]
 pub struct Boot2Opt {
     /// Path to partition_cfg.toml, default to be partition/partition_cfg_2M.toml
     #[structopt(long, parse(from_os_str))]
@@ -57,27 +104,61 @@ pub struct Boot2Opt {
     /// Without boot2
     #[structopt(short, long)]
     pub without_boot2: bool,
+    /// Path to blsp_boot2 to use instead of the bundled one,
+    /// required for chips without a bundled boot2
+    #[structopt(long, parse(from_os_str), conflicts_with = "without-boot2")]
+    pub boot2: Option<PathBuf>,
+    /// Write an ELF's flash regions at their offsets as they are, without boot2 or a boot header
+    #[structopt(long, conflicts_with = "without-boot2")]
+    pub no_boot_header: bool,
 }
 
 #[derive(StructOpt)]
 pub struct FlashOpt {
     #[structopt(flatten)]
     pub conn: Connection,
-    /// Bin file
-    #[structopt(parse(from_os_str))]
-    pub image: PathBuf,
+    /// Bin, ELF, Intel HEX, S-record or UF2 file
+    #[structopt(parse(from_os_str), required_unless_one = &["m0", "d0", "lp"])]
+    pub image: Option<PathBuf>,
+    /// M0 core image for multi-core chips like BL808, placed in the FW partition
+    #[structopt(long, parse(from_os_str), conflicts_with = "image")]
+    pub m0: Option<PathBuf>,
+    /// D0 core image, placed in the D0FW partition
+    #[structopt(long, parse(from_os_str), conflicts_with = "image")]
+    pub d0: Option<PathBuf>,
+    /// LP core image, placed in the LPFW partition
+    #[structopt(long, parse(from_os_str), conflicts_with = "image")]
+    pub lp: Option<PathBuf>,
     /// Don't skip if hash matches
     #[structopt(short, long)]
     pub force: bool,
+    /// Send segments uncompressed
+    #[structopt(long)]
+    pub no_compress: bool,
     #[structopt(flatten)]
     pub boot: Boot2Opt,
 }
 
+#[derive(StructOpt)]
+pub struct FlashManyOpt {
+    /// Serial ports or glob patterns like "/dev/ttyUSB*", comma separated or repeated.
+    /// --port is ignored
+    #[structopt(
+        long = "ports",
+        required = true,
+        number_of_values = 1,
+        use_delimiter = true
+    )]
+    pub ports: Vec<String>,
+    #[structopt(flatten)]
+    pub flash: FlashOpt,
+}
+
 #[derive(StructOpt)]
 pub struct CheckOpt {
     #[structopt(flatten)]
     pub conn: Connection,
-    /// Bin file
+    /// Bin, ELF, Intel HEX, S-record or UF2 file
     #[structopt(parse(from_os_str))]
     pub image: PathBuf,
     #[structopt(flatten)]
@@ -94,9 +175,24 @@ pub struct DumpOpt {
     /// start address
     #[structopt(parse(try_from_str = parse_int::parse), default_value = "0")]
     pub start: u32,
-    /// end address
-    #[structopt(parse(try_from_str = parse_int::parse), default_value = "0x100000")]
-    pub end: u32,
+    /// end address, default to the end of flash
+    #[structopt(parse(try_from_str = parse_int::parse))]
+    pub end: Option<u32>,
+    /// Path to efuse_bootheader_cfg.conf, for the flash timing
+    #[structopt(long, parse(from_os_str))]
+    pub boot_header_cfg: Option<PathBuf>,
+}
+
+#[derive(StructOpt)]
+pub struct RunOpt {
+    #[structopt(flatten)]
+    pub conn: Connection,
+    /// RAM-linked ELF file
+    #[structopt(parse(from_os_str))]
+    pub image: PathBuf,
+    /// Path to efuse_bootheader_cfg.conf
+    #[structopt(long, parse(from_os_str))]
+    pub boot_header_cfg: Option<PathBuf>,
 }
 
 #[derive(StructOpt)]
@@ -108,150 +204,499 @@ pub struct ResetOpt {
     pub loader: bool,
 }
 
+#[derive(StructOpt)]
+pub struct EraseOpt {
+    #[structopt(flatten)]
+    pub conn: Connection,
+    /// Erase the whole chip
+    #[structopt(long, conflicts_with_all = &["start", "end", "partition"])]
+    pub all: bool,
+    /// start address
+    #[structopt(long, parse(try_from_str = parse_int::parse), requires = "end")]
+    pub start: Option<u32>,
+    /// end address
+    #[structopt(long, parse(try_from_str = parse_int::parse), requires = "start")]
+    pub end: Option<u32>,
+    /// Name of the partition to erase
+    #[structopt(long, conflicts_with_all = &["start", "end"])]
+    pub partition: Option<String>,
+    /// Path to partition_cfg.toml, default to be partition/partition_cfg_2M.toml
+    #[structopt(long, parse(from_os_str))]
+    pub partition_cfg: Option<PathBuf>,
+    /// Path to efuse_bootheader_cfg.conf, for the erase timeouts
+    #[structopt(long, parse(from_os_str))]
+    pub boot_header_cfg: Option<PathBuf>,
+}
+
+#[derive(StructOpt)]
+pub struct MemOpt {
+    #[structopt(flatten)]
+    pub conn: Connection,
+    #[structopt(subcommand)]
+    pub op: MemOp,
+}
+
+#[derive(StructOpt)]
+pub enum MemOp {
+    /// Read 32-bit words
+    Read {
+        /// memory address
+        #[structopt(parse(try_from_str = parse_int::parse))]
+        addr: u32,
+        /// length in bytes
+        #[structopt(long, parse(try_from_str = parse_int::parse), default_value = "4")]
+        len: u32,
+        /// Print a hexdump instead of words
+        #[structopt(long)]
+        hexdump: bool,
+    },
+    /// Write a 32-bit word
+    Write {
+        /// memory address
+        #[structopt(parse(try_from_str = parse_int::parse))]
+        addr: u32,
+        /// value
+        #[structopt(parse(try_from_str = parse_int::parse))]
+        value: u32,
+    },
+}
+
+#[derive(StructOpt)]
+pub struct EfuseOpt {
+    #[structopt(flatten)]
+    pub conn: Connection,
+    #[structopt(subcommand)]
+    pub op: EfuseOp,
+}
+
+#[derive(StructOpt)]
+pub enum EfuseOp {
+    /// Read efuse and decode the known fields
+    Read {
+        /// start address
+        #[structopt(long, parse(try_from_str = parse_int::parse), default_value = "0")]
+        start: u32,
+        /// length, default to the rest of the efuse on chips with a known efuse map
+        #[structopt(long, parse(try_from_str = parse_int::parse))]
+        len: Option<u32>,
+    },
+    /// Program hex bytes at an address
+    Write {
+        /// efuse address
+        #[structopt(parse(try_from_str = parse_int::parse))]
+        addr: u32,
+        /// hex data
+        data: String,
+        /// Really blow the fuses, this can't be undone
+        #[structopt(long)]
+        confirm: bool,
+    },
+    /// Program only the bits of hex data selected by a hex mask
+    Mask {
+        /// efuse address
+        #[structopt(parse(try_from_str = parse_int::parse))]
+        addr: u32,
+        /// hex data
+        data: String,
+        /// hex mask
+        mask: String,
+        /// Really blow the fuses, this can't be undone
+        #[structopt(long)]
+        confirm: bool,
+    },
+}
+
 #[derive(StructOpt)]
 pub enum Opt {
     /// Flash image to serial
     Flash(FlashOpt),
+    /// Flash the same image to several devices in parallel
+    FlashMany(FlashManyOpt),
     /// Check if the device's flash matches the image
     Check(CheckOpt),
     /// Dump the whole flash to a file
     Dump(DumpOpt),
+    /// Load a RAM-linked ELF through the boot ROM and run it, leaving flash untouched
+    Run(RunOpt),
     /// Reset chip
     Reset(ResetOpt),
+    /// Erase the whole flash, an address range or a partition
+    Erase(EraseOpt),
+    /// Read or program efuse
+    Efuse(EfuseOpt),
+    /// Read or write memory and registers
+    Mem(MemOpt),
 }
 
 impl Connection {
+    /// The given port, or the detected one
+    pub fn port(&self) -> Result<String, Error> {
+        match &self.port {
+            Some(port) => Ok(port.clone()),
+            None => Ok(detect::detect_port()?.to_string_lossy().into_owned()),
+        }
+    }
     pub fn open_serial(&self) -> Result<impl SerialPort, Error> {
-        let mut serial = serial::open(&self.port)?;
-        serial.reconfigure(&|setup: &mut dyn SerialPortSettings| {
-            setup.set_char_size(CharSize::Bits8);
-            setup.set_stop_bits(StopBits::Stop1);
-            setup.set_parity(Parity::ParityNone);
-            setup.set_flow_control(FlowControl::FlowNone);
-            Ok(())
-        })?;
-        Ok(serial)
+        open_serial(&self.port()?)
+    }
+    pub fn open_transport(&self) -> Result<Box<dyn Transport>, Error> {
+        let port = self.port()?;
+        Ok(if let Some(addr) = port.strip_prefix("tcp://") {
+            Box::new(TcpTransport::connect(addr)?)
+        } else if let Some(path) = port.strip_prefix("replay://") {
+            Box::new(ReplayTransport::open(path)?)
+        } else {
+            Box::new(open_serial(&port)?)
+        })
+    }
+    pub fn open_trace(&self) -> Result<Option<TraceRecorder>, Error> {
+        self.trace.as_ref().map(TraceRecorder::create).transpose()
     }
+    /// The board preset with the pins and sequences given on the command line
+    pub fn reset_config(&self) -> Result<ResetConfig, Error> {
+        let mut config = ResetConfig::preset(&self.board)?;
+        if let Some(pin) = &self.reset_pin {
+            config.reset_pin = pin.parse()?;
+        }
+        if let Some(pin) = &self.boot_pin {
+            config.boot_pin = pin.parse()?;
+        }
+        if let Some(sequence) = &self.flash_sequence {
+            config.to_flash = sequence.parse()?;
+        }
+        if let Some(sequence) = &self.run_sequence {
+            config.to_run = sequence.parse()?;
+        }
+        Ok(config)
+    }
+
     pub fn create_flasher(&self) -> Result<Flasher, Error> {
-        let serial = self.open_serial()?;
-        Flasher::connect(
-            self.chip.clone(),
-            serial,
+        let eflash_loader = self.eflash_loader.as_ref().map(read).transpose()?;
+        let transport = self.open_transport()?;
+        let mut flasher = Flasher::connect(
+            self.chip.chip(),
+            transport,
             BaudRate::from_speed(self.initial_baud_rate),
             BaudRate::from_speed(self.baud_rate),
-            self.reset_pin.clone(),
-            self.boot_pin.clone(),
-        )
+            self.reset_config()?,
+            self.open_trace()?,
+        )?;
+        flasher.set_pipeline(!self.no_pipeline);
+        flasher.set_fatal_checksum_errors(self.fatal_checksum_errors);
+        if let Some(loader) = eflash_loader {
+            flasher.set_eflash_loader(loader);
+        }
+        Ok(flasher)
     }
 }
 
 impl Boot2Opt {
     pub fn with_boot2<'a>(
         self,
-        chip: &'a Box<dyn Chip>,
+        chip: &'a dyn Chip,
         image: &[u8],
     ) -> Result<Vec<RomSegment<'a>>, Error> {
         let partition_cfg = self
             .partition_cfg
             .map(read)
-            .unwrap_or_else(|| Ok(chip::bl602::DEFAULT_PARTITION_CFG.to_vec()))?;
+            .unwrap_or_else(|| Ok(chip.get_default_partition_cfg().to_vec()))?;
         let boot_header_cfg = self
             .boot_header_cfg
             .map(read)
-            .unwrap_or_else(|| Ok(chip::bl602::DEFAULT_BOOTHEADER_CFG.to_vec()))?;
+            .unwrap_or_else(|| Ok(chip.get_default_boot_header_cfg().to_vec()))?;
         let partition_cfg = toml::from_slice(&partition_cfg)?;
-        let BootHeaderCfgFile { boot_header_cfg } = toml::from_slice(&boot_header_cfg)?;
+        let boot_header_cfg = chip.parse_boot_header_cfg(&boot_header_cfg)?;
         let ro_params = self
             .dtb
             .map(read)
-            .unwrap_or_else(|| Ok(chip::bl602::RO_PARAMS.to_vec()))?;
+            .unwrap_or_else(|| Ok(chip.get_ro_params().to_vec()))?;
+        let boot2 = match self.boot2 {
+            Some(path) => read(path)?,
+            None => chip.get_boot2().ok_or(Error::MissingBoot2)?.to_vec(),
+        };
 
-        let segments = chip.with_boot2(partition_cfg, boot_header_cfg, ro_params, image)?;
+        let segments = chip.with_boot2(partition_cfg, boot_header_cfg, ro_params, &boot2, image)?;
 
         Ok(segments)
     }
+    /// Boot group headers and partition table, with each core's image in its partition
+    pub fn with_cores<'a>(
+        self,
+        chip: &'a dyn Chip,
+        images: Vec<(&str, Vec<u8>)>,
+    ) -> Result<Vec<RomSegment<'a>>, Error> {
+        let partition_cfg = self
+            .partition_cfg
+            .map(read)
+            .unwrap_or_else(|| Ok(chip.get_default_partition_cfg().to_vec()))?;
+        let boot_header_cfg = self
+            .boot_header_cfg
+            .map(read)
+            .unwrap_or_else(|| Ok(chip.get_default_boot_header_cfg().to_vec()))?;
+        let partition_cfg = toml::from_slice(&partition_cfg)?;
+
+        chip.with_cores(partition_cfg, &boot_header_cfg, images)
+    }
+    /// Flash timing from the boot header config, used for erase and program timeouts
+    pub fn flash_timing(&self, chip: &dyn Chip) -> Result<FlashTiming, Error> {
+        flash_timing(chip, self.boot_header_cfg.as_deref())
+    }
     pub fn make_segment<'a>(
         self,
-        _chip: &'a Box<dyn Chip>,
+        chip: &'a dyn Chip,
         image: Vec<u8>,
     ) -> Result<RomSegment<'a>, Error> {
         let boot_header_cfg = self
             .boot_header_cfg
             .map(read)
-            .unwrap_or_else(|| Ok(chip::bl602::DEFAULT_BOOTHEADER_CFG.to_vec()))?;
-        let BootHeaderCfgFile {
-            mut boot_header_cfg,
-        } = toml::from_slice(&boot_header_cfg)?;
+            .unwrap_or_else(|| Ok(chip.get_default_boot_header_cfg().to_vec()))?;
+        let mut boot_header_cfg = chip.parse_boot_header_cfg(&boot_header_cfg)?;
         let img = boot_header_cfg.make_image(0x2000, image)?;
 
         Ok(RomSegment::from_vec(0x0, img))
     }
+    /// Segments for a bin or ELF, as they are with `no_boot_header` or else behind a boot header
+    pub fn image_segments<'a>(
+        self,
+        chip: &'a dyn Chip,
+        format: Format,
+        image: &'a [u8],
+    ) -> Result<Vec<RomSegment<'a>>, Error> {
+        if self.no_boot_header {
+            return read_segments(chip, format, image);
+        }
+        let image = read_image(chip, format, image)?;
+        self.get_segments(chip, Vec::from(image))
+    }
     pub fn get_segments<'a>(
         self,
-        chip: &'a Box<dyn Chip>,
+        chip: &'a dyn Chip,
         image: Vec<u8>,
     ) -> Result<Vec<RomSegment<'a>>, Error> {
         Ok(if self.without_boot2 {
-            vec![self.make_segment(chip, Vec::from(image))?]
+            vec![self.make_segment(chip, image)?]
         } else {
             self.with_boot2(chip, &image)?
         })
     }
 }
 
-pub fn read_image<'a>(chip: &Box<dyn Chip>, image: &'a [u8]) -> Result<Cow<'a, [u8]>, Error> {
-    Ok(if image[0..4] == [0x7f, 0x45, 0x4c, 0x46] {
-        log::trace!("Detect ELF");
-        // ELF
-        let firmware_image = FirmwareImage::from_data(image).map_err(|_| Error::InvalidElf)?;
-        Cow::Owned(firmware_image.to_flash_bin(chip))
-    } else {
-        // bin
-        Cow::Borrowed(image)
+/// Files given to flash, one image or one per core
+enum FlashImages {
+    Single(Format, Vec<u8>),
+    Cores(Vec<(&'static str, Format, Vec<u8>)>),
+}
+
+impl FlashOpt {
+    fn read_images(&self) -> Result<FlashImages, Error> {
+        if let Some(image) = &self.image {
+            let (format, image) = read_file(image)?;
+            return Ok(FlashImages::Single(format, image));
+        }
+        let cores = [("m0", &self.m0), ("d0", &self.d0), ("lp", &self.lp)];
+        let images = cores
+            .iter()
+            .filter_map(|(core, path)| Some((*core, path.as_ref()?)))
+            .map(|(core, path)| {
+                let (format, image) = read_file(path)?;
+                Ok((core, format, image))
+            })
+            .collect::<Result<_, Error>>()?;
+        Ok(FlashImages::Cores(images))
+    }
+}
+
+impl FlashImages {
+    fn segments<'a>(
+        &'a self,
+        chip: &'a dyn Chip,
+        boot: Boot2Opt,
+    ) -> Result<Vec<RomSegment<'a>>, Error> {
+        match self {
+            FlashImages::Single(format, image) => boot.image_segments(chip, *format, image),
+            FlashImages::Cores(images) => {
+                let images = images
+                    .iter()
+                    .map(|(core, format, image)| {
+                        Ok((*core, read_image(chip, *format, image)?.into_owned()))
+                    })
+                    .collect::<Result<_, Error>>()?;
+                boot.with_cores(chip, images)
+            }
+        }
+    }
+}
+
+fn open_serial(port: &str) -> Result<impl SerialPort, Error> {
+    let mut serial = serial::open(port)?;
+    serial.reconfigure(&|setup: &mut dyn SerialPortSettings| {
+        setup.set_char_size(CharSize::Bits8);
+        setup.set_stop_bits(StopBits::Stop1);
+        setup.set_parity(Parity::ParityNone);
+        setup.set_flow_control(FlowControl::FlowNone);
+        Ok(())
+    })?;
+    Ok(serial)
+}
+
+/// The file's content and its format, from the extension or the content
+pub fn read_file(path: &Path) -> Result<(Format, Vec<u8>), Error> {
+    let image = read(path)?;
+    let format = Format::detect(Some(path), &image);
+    log::trace!("Detect {:?}", format);
+    Ok((format, image))
+}
+
+/// The image as one bin from flash offset 0
+pub fn read_image<'a>(
+    chip: &dyn Chip,
+    format: Format,
+    image: &'a [u8],
+) -> Result<Cow<'a, [u8]>, Error> {
+    Ok(match format {
+        Format::Elf => {
+            let firmware_image = FirmwareImage::from_data(image).map_err(|_| Error::InvalidElf)?;
+            Cow::Owned(firmware_image.to_flash_bin(chip))
+        }
+        Format::Bin => Cow::Borrowed(image),
+        _ => {
+            let segments = format::read_segments(chip, format, image)?;
+            Cow::Owned(segments_to_bin(&segments))
+        }
+    })
+}
+
+/// The image's contiguous regions at their flash offsets, a bin is one region at offset 0
+pub fn read_segments<'a>(
+    chip: &dyn Chip,
+    format: Format,
+    image: &'a [u8],
+) -> Result<Vec<RomSegment<'a>>, Error> {
+    Ok(match format {
+        Format::Elf => {
+            let firmware_image = FirmwareImage::from_data(image).map_err(|_| Error::InvalidElf)?;
+            firmware_image.to_flash_segments(chip)
+        }
+        Format::Bin => vec![RomSegment::from_slice(0, image)],
+        _ => format::read_segments(chip, format, image)?,
     })
 }
 
 pub fn flash(opt: FlashOpt) -> Result<(), Error> {
-    let chip = opt.conn.chip.clone().to_box();
-    let image = read(&opt.image)?;
-    let image = read_image(&chip, &image)?;
+    let images = opt.read_images()?;
 
     let mut flasher = opt.conn.create_flasher()?;
     log::info!("Bootrom version: {}", flasher.boot_info().bootrom_version);
     log::trace!("Boot info: {:x?}", flasher.boot_info());
 
-    let segments = opt.boot.get_segments(&chip, Vec::from(image))?;
+    let chip = flasher.chip().to_box();
+    flasher.set_compress(!opt.no_compress);
+    flasher.set_flash_timing(opt.boot.flash_timing(&*chip)?);
+    let segments = images.segments(&*chip, opt.boot)?;
     flasher.load_segments(opt.force, segments.into_iter())?;
     flasher.reset()?;
 
-    log::info!("Success");
+    match flasher.baud_rate() {
+        Some(speed) => log::info!("Success at {} baud", speed.speed()),
+        None => log::info!("Success"),
+    }
 
     Ok(())
 }
 
+pub fn flash_many(opt: FlashManyOpt) -> Result<(), Error> {
+    let FlashManyOpt { ports, flash: opt } = opt;
+    let ports = multi::expand_ports(&ports)?;
+    if ports.is_empty() {
+        return Err(Error::NoSerialPort);
+    }
+    let images = opt.read_images()?;
+    let (force, compress) = (opt.force, !opt.no_compress);
+    let (conn, boot) = (opt.conn, opt.boot);
+
+    let devices = ports
+        .into_iter()
+        .enumerate()
+        .map(|(i, port)| {
+            let mut conn = conn.clone();
+            conn.port = Some(port.clone());
+            // one trace per device, numbered in port order
+            conn.trace = conn.trace.map(|trace| {
+                let mut trace = trace.into_os_string();
+                trace.push(format!(".{}", i));
+                trace.into()
+            });
+            (port, conn)
+        })
+        .collect();
+
+    let results = multi::run_parallel(devices, |conn: Connection, bar| {
+        let mut flasher = conn.create_flasher()?;
+        // each device has its own chip, with auto they may differ
+        let chip = flasher.chip().to_box();
+        flasher.set_compress(compress);
+        flasher.set_flash_timing(boot.flash_timing(&*chip)?);
+        flasher.set_progress_bar(bar.clone());
+        let segments = images.segments(&*chip, boot.clone())?;
+        flasher.load_segments(force, segments.into_iter())?;
+        flasher.reset()
+    });
+    multi::summarize(&results)
+}
+
 pub fn check(opt: CheckOpt) -> Result<(), Error> {
-    let chip = opt.conn.chip.clone().to_box();
-    let image = read(&opt.image)?;
-    let image = read_image(&chip, &image)?;
+    let (format, image) = read_file(&opt.image)?;
 
     let mut flasher = opt.conn.create_flasher()?;
     log::info!("Bootrom version: {}", flasher.boot_info().bootrom_version);
     log::trace!("Boot info: {:x?}", flasher.boot_info());
 
-    let segments = opt.boot.get_segments(&chip, Vec::from(image))?;
+    let chip = flasher.chip().to_box();
+    flasher.set_flash_timing(opt.boot.flash_timing(&*chip)?);
+    let segments = opt.boot.image_segments(&*chip, format, &image)?;
     flasher.check_segments(segments.into_iter())?;
 
     Ok(())
 }
 
+pub fn run(opt: RunOpt) -> Result<(), Error> {
+    let image = read(&opt.image)?;
+    let firmware_image = FirmwareImage::from_data(&image).map_err(|_| Error::InvalidElf)?;
+
+    let mut flasher = opt.conn.create_flasher()?;
+    log::info!("Bootrom version: {}", flasher.boot_info().bootrom_version);
+    log::trace!("Boot info: {:x?}", flasher.boot_info());
+
+    let chip = flasher.chip().to_box();
+    let segments = firmware_image.to_ram_segments(&*chip)?;
+
+    let boot_header_cfg = opt
+        .boot_header_cfg
+        .map(read)
+        .unwrap_or_else(|| Ok(chip.get_default_boot_header_cfg().to_vec()))?;
+    let mut boot_header_cfg = chip.parse_boot_header_cfg(&boot_header_cfg)?;
+    let ram_image = boot_header_cfg.make_ram_image(firmware_image.entry(), &segments)?;
+
+    flasher.run_ram_image(&ram_image)?;
+    log::info!("Running from {:#010x}", firmware_image.entry());
+
+    Ok(())
+}
+
 pub fn dump(opt: DumpOpt) -> Result<(), Error> {
     let mut output = File::create(opt.output)?;
     let mut flasher = opt.conn.create_flasher()?;
+    let chip = flasher.chip().to_box();
+    flasher.set_flash_timing(flash_timing(&*chip, opt.boot_header_cfg.as_deref())?);
 
     log::info!("Bootrom version: {}", flasher.boot_info().bootrom_version);
     log::trace!("Boot info: {:x?}", flasher.boot_info());
 
-    flasher.dump_flash(opt.start..opt.end, &mut output)?;
+    let end = match opt.end {
+        Some(end) => end,
+        None => flasher.flash_info()?.size,
+    };
+    flasher.dump_flash(opt.start..end, &mut output)?;
 
     log::info!("Success");
 
@@ -259,8 +704,8 @@ pub fn dump(opt: DumpOpt) -> Result<(), Error> {
 }
 
 pub fn reset(opt: ResetOpt) -> Result<(), Error> {
-    let serial = opt.conn.open_serial()?;
-    let mut conn = connection::Connection::new(serial, opt.conn.reset_pin, opt.conn.boot_pin);
+    let transport = opt.conn.open_transport()?;
+    let mut conn = connection::Connection::new(transport, opt.conn.reset_config()?);
 
     if opt.loader {
         conn.reset_to_flash().expect("reset error")
@@ -272,3 +717,176 @@ pub fn reset(opt: ResetOpt) -> Result<(), Error> {
 
     Ok(())
 }
+
+pub fn erase(opt: EraseOpt) -> Result<(), Error> {
+    if !opt.all && opt.start.is_none() && opt.partition.is_none() {
+        return Err(Error::ArgsError);
+    }
+    let mut flasher = opt.conn.create_flasher()?;
+    log::info!("Bootrom version: {}", flasher.boot_info().bootrom_version);
+    log::trace!("Boot info: {:x?}", flasher.boot_info());
+
+    let chip = flasher.chip().to_box();
+    flasher.set_flash_timing(flash_timing(&*chip, opt.boot_header_cfg.as_deref())?);
+    let ranges = if opt.all {
+        vec![]
+    } else if let (Some(start), Some(end)) = (opt.start, opt.end) {
+        vec![Range { start, end }]
+    } else if let Some(name) = opt.partition {
+        let partition_cfg = opt
+            .partition_cfg
+            .map(read)
+            .unwrap_or_else(|| Ok(chip.get_default_partition_cfg().to_vec()))?;
+        let partition_cfg: PartitionCfg = toml::from_slice(&partition_cfg)?;
+        let entry = partition_cfg
+            .pt_entry
+            .iter()
+            .find(|entry| entry.name == name)
+            .ok_or_else(|| Error::MissingPartition(name.clone()))?;
+        [(entry.address0, entry.size0), (entry.address1, entry.size1)]
+            .iter()
+            .filter(|(_, size)| *size > 0)
+            .map(|&(addr, size)| {
+                let end = addr
+                    .checked_add(size)
+                    .ok_or(Error::RangeOverflow { addr, size })?;
+                Ok(addr..end)
+            })
+            .collect::<Result<_, Error>>()?
+    } else {
+        return Err(Error::ArgsError);
+    };
+
+    if opt.all {
+        flasher.erase_chip()?;
+    }
+    for range in ranges {
+        let erased = flasher.erase_flash(range)?;
+        log::info!("Erased {:#x}..{:#x}", erased.start, erased.end);
+    }
+
+    log::info!("Success");
+
+    Ok(())
+}
+
+/// Flash timing from `boot_header_cfg`, or from the chip's default one
+fn flash_timing(chip: &dyn Chip, boot_header_cfg: Option<&Path>) -> Result<FlashTiming, Error> {
+    let boot_header_cfg = boot_header_cfg
+        .map(read)
+        .unwrap_or_else(|| Ok(chip.get_default_boot_header_cfg().to_vec()))?;
+
+    Ok(chip.parse_boot_header_cfg(&boot_header_cfg)?.flash_timing())
+}
+
+fn hexdump(start: u32, data: &[u8]) {
+    for (i, line) in data.chunks(16).enumerate() {
+        let ascii: String = line
+            .iter()
+            .map(|&b| if b.is_ascii_graphic() { b as char } else { '.' })
+            .collect();
+        log::info!(
+            "{:08x}: {:<32} {}",
+            start as usize + i * 16,
+            hex::encode(line),
+            ascii
+        );
+    }
+}
+
+pub fn mem(opt: MemOpt) -> Result<(), Error> {
+    let mut flasher = opt.conn.create_flasher()?;
+
+    match opt.op {
+        MemOp::Read {
+            addr,
+            len,
+            hexdump: true,
+        } => {
+            let data = flasher.read_memory(addr, len)?;
+            hexdump(addr, &data);
+        }
+        MemOp::Read { addr, len, .. } => {
+            let data = flasher.read_memory(addr, len)?;
+            for (i, word) in data.chunks(4).enumerate() {
+                log::info!(
+                    "{:08x}: {:08x}",
+                    addr as usize + i * 4,
+                    u32::from_le_bytes([word[0], word[1], word[2], word[3]])
+                );
+            }
+        }
+        MemOp::Write { addr, value } => {
+            flasher.write_word(addr, value)?;
+            log::info!("{:08x} <- {:08x}", addr, value);
+        }
+    }
+
+    Ok(())
+}
+
+fn parse_hex(s: &str) -> Result<Vec<u8>, Error> {
+    hex::decode(s.trim_start_matches("0x")).map_err(|_| Error::ArgsError)
+}
+
+pub fn efuse(opt: EfuseOpt) -> Result<(), Error> {
+    let (addr, data, mask) = match opt.op {
+        EfuseOp::Read { start, len } => {
+            let mut flasher = opt.conn.create_flasher()?;
+            let chip = flasher.chip();
+            let layout = chip.clone().to_box().efuse_layout();
+            let len = match (len, layout) {
+                (Some(len), _) => len,
+                (None, Some(layout)) => layout.size.saturating_sub(start),
+                (None, None) => {
+                    log::error!("No efuse map for {}, pass --len", chip.name());
+                    return Err(Error::ArgsError);
+                }
+            };
+            let efuse = flasher.read_efuse(start, len)?;
+
+            hexdump(start, &efuse);
+            match layout {
+                Some(layout) => {
+                    for line in layout.decode(&efuse, start) {
+                        log::info!("{}", line);
+                    }
+                }
+                None => log::info!("No efuse map for {}, fields not decoded", chip.name()),
+            }
+            return Ok(());
+        }
+        EfuseOp::Write {
+            addr,
+            data,
+            confirm,
+        } => {
+            let data = parse_hex(&data)?;
+            let mask = vec![0xff; data.len()];
+            if !confirm {
+                return Err(Error::ConfirmationRequired);
+            }
+            (addr, data, mask)
+        }
+        EfuseOp::Mask {
+            addr,
+            data,
+            mask,
+            confirm,
+        } => {
+            let data = parse_hex(&data)?;
+            let mask = parse_hex(&mask)?;
+            if !confirm {
+                return Err(Error::ConfirmationRequired);
+            }
+            (addr, data, mask)
+        }
+    };
+
+    let mut flasher = opt.conn.create_flasher()?;
+    flasher.program_efuse(addr, &data, &mask)?;
+
+    log::info!("Success");
+
+    Ok(())
+}
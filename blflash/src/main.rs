@@ -1,4 +1,4 @@
-use blflash::{check, dump, flash, reset, Opt};
+use blflash::{check, dump, efuse, erase, flash, flash_many, mem, reset, run, Opt};
 use env_logger::Env;
 use main_error::MainError;
 
@@ -10,9 +10,14 @@ fn main(args: Opt) -> Result<(), MainError> {
 
     match args {
         Opt::Flash(opt) => flash(opt)?,
+        Opt::FlashMany(opt) => flash_many(opt)?,
         Opt::Check(opt) => check(opt)?,
         Opt::Dump(opt) => dump(opt)?,
+        Opt::Run(opt) => run(opt)?,
         Opt::Reset(opt) => reset(opt)?,
+        Opt::Erase(opt) => erase(opt)?,
+        Opt::Efuse(opt) => efuse(opt)?,
+        Opt::Mem(opt) => mem(opt)?,
     };
 
     Ok(())
@@ -0,0 +1,109 @@
+//! Running the same job on several devices at once.
+//!
+//! Every device gets a thread and a line in a shared progress view, a failing
+//! device doesn't stop the others.
+
+use crate::Error;
+use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+use std::{panic, thread};
+
+/// Ports given on the command line, with glob patterns like `/dev/ttyUSB*` expanded
+pub fn expand_ports(patterns: &[String]) -> Result<Vec<String>, Error> {
+    let mut ports = Vec::new();
+    for pattern in patterns {
+        let matches = if pattern.contains("://") || !pattern.contains(['*', '?', '[']) {
+            vec![pattern.clone()]
+        } else {
+            glob::glob(pattern)
+                .map_err(|_| Error::ArgsError)?
+                .map(|path| {
+                    Ok(path
+                        .map_err(|e| e.into_error())?
+                        .to_string_lossy()
+                        .into_owned())
+                })
+                .collect::<Result<Vec<_>, Error>>()?
+        };
+        if matches.is_empty() {
+            log::warn!("No port matches {}", pattern);
+        }
+        for port in matches {
+            if !ports.contains(&port) {
+                ports.push(port);
+            }
+        }
+    }
+    Ok(ports)
+}
+
+fn device_bar(name: &str) -> ProgressBar {
+    let bar = ProgressBar::new(0);
+    bar.set_style(
+        ProgressStyle::default_bar()
+            .template("  {prefix:<16} {msg:<12} {wide_bar} {bytes}/{total_bytes}  ")
+            .progress_chars("#>-"),
+    );
+    bar.set_prefix(name.to_string());
+    bar
+}
+
+pub struct DeviceResult {
+    pub name: String,
+    pub result: Result<(), Error>,
+}
+
+/// Run `job` on every named device, each on its own thread with its own progress line
+pub fn run_parallel<T, F>(devices: Vec<(String, T)>, job: F) -> Vec<DeviceResult>
+where
+    T: Send,
+    F: Fn(T, &ProgressBar) -> Result<(), Error> + Sync,
+{
+    let multi = MultiProgress::new();
+    let job = &job;
+    thread::scope(|scope| {
+        let handles: Vec<_> = devices
+            .into_iter()
+            .map(|(name, device)| {
+                let bar = multi.add(device_bar(&name));
+                let handle = scope.spawn(move || {
+                    let result = job(device, &bar);
+                    match &result {
+                        Ok(()) => bar.finish_with_message("pass"),
+                        Err(e) => bar.abandon_with_message(format!("FAIL: {}", e)),
+                    }
+                    result
+                });
+                (name, handle)
+            })
+            .collect();
+        // draws until every bar is finished
+        if let Err(e) = multi.join() {
+            log::warn!("Progress view failed: {}", e);
+        }
+        handles
+            .into_iter()
+            .map(|(name, handle)| DeviceResult {
+                name,
+                result: handle.join().unwrap_or_else(|e| panic::resume_unwind(e)),
+            })
+            .collect()
+    })
+}
+
+/// Log a pass/fail line per device, failing if any device did
+pub fn summarize(results: &[DeviceResult]) -> Result<(), Error> {
+    for DeviceResult { name, result } in results {
+        match result {
+            Ok(()) => log::info!("{:<16} pass", name),
+            Err(e) => log::error!("{:<16} FAIL: {}", name, e),
+        }
+    }
+    let failed = results.iter().filter(|r| r.result.is_err()).count();
+    if failed > 0 {
+        return Err(Error::DevicesFailed {
+            failed,
+            total: results.len(),
+        });
+    }
+    Ok(())
+}
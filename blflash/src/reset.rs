@@ -0,0 +1,193 @@
+//! Reset and boot strap pin control.
+//!
+//! A sequence is a comma separated list of steps:
+//!
+//! ```text
+//! boot=1,reset=1,wait 50,reset=0,wait 100,boot=0
+//! ```
+//!
+//! `boot` and `reset` drive the configured pins, `rts` and `dtr` drive the
+//! lines directly, and `wait` sleeps for the given number of milliseconds.
+
+use crate::{transport::Transport, Error};
+use std::{str::FromStr, thread::sleep, time::Duration};
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Line {
+    Rts,
+    Dtr,
+    Null,
+}
+
+/// A control line, `!` in front of its name inverts it
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Pin {
+    pub line: Line,
+    pub inverted: bool,
+}
+
+impl FromStr for Pin {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let inverted = s.starts_with('!');
+        let line = match s.trim_start_matches('!') {
+            "rts" => Line::Rts,
+            "dtr" => Line::Dtr,
+            "null" => Line::Null,
+            _ => return Err(Error::ArgsError),
+        };
+        Ok(Pin { line, inverted })
+    }
+}
+
+impl Pin {
+    fn set(self, transport: &mut dyn Transport, level: bool) -> Result<(), Error> {
+        let level = level != self.inverted;
+        match self.line {
+            Line::Rts => transport.set_rts(level),
+            Line::Dtr => transport.set_dtr(level),
+            Line::Null => Ok(()),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Step {
+    Boot(bool),
+    Reset(bool),
+    Line(Pin, bool),
+    Wait(Duration),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Sequence(pub Vec<Step>);
+
+fn parse_level(s: &str) -> Result<bool, Error> {
+    match s.trim() {
+        "0" => Ok(false),
+        "1" => Ok(true),
+        _ => Err(Error::ArgsError),
+    }
+}
+
+impl FromStr for Step {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        if let Some(ms) = s.strip_prefix("wait") {
+            let ms = ms.trim_start_matches([' ', '=']);
+            let ms = ms.parse().map_err(|_| Error::ArgsError)?;
+            return Ok(Step::Wait(Duration::from_millis(ms)));
+        }
+        let (name, level) = s.split_once('=').ok_or(Error::ArgsError)?;
+        let level = parse_level(level)?;
+        Ok(match name.trim() {
+            "boot" => Step::Boot(level),
+            "reset" => Step::Reset(level),
+            pin => Step::Line(pin.parse()?, level),
+        })
+    }
+}
+
+impl FromStr for Sequence {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.split(',')
+            .filter(|step| !step.trim().is_empty())
+            .map(str::parse)
+            .collect::<Result<_, _>>()
+            .map(Sequence)
+    }
+}
+
+/// How the chip's reset and boot strap pins are wired and toggled
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ResetConfig {
+    pub reset_pin: Pin,
+    pub boot_pin: Pin,
+    /// Reset into the boot ROM's UART download mode
+    pub to_flash: Sequence,
+    /// Reset and run the firmware in flash
+    pub to_run: Sequence,
+}
+
+/// Board wirings, as (name, reset pin, boot pin, to_flash, to_run)
+pub const PRESETS: &[(&str, &str, &str, &str, &str)] = &[
+    (
+        "default",
+        "rts",
+        "!dtr",
+        "boot=1,wait 10,reset=1,wait 10,reset=0,wait 10,boot=0,wait 10",
+        "boot=0,wait 10,reset=1,wait 10,reset=0,wait 10",
+    ),
+    // the boot strap is a jumper and reset a button, so only leave time to press it
+    ("pinecone", "null", "null", "wait 2000", "wait 2000"),
+    (
+        "sipeed-m0",
+        "rts",
+        "dtr",
+        "boot=1,reset=1,wait 50,reset=0,wait 100,boot=0",
+        "boot=0,reset=1,wait 50,reset=0",
+    ),
+    // the onboard BL702 bridge needs longer pulses
+    (
+        "sipeed-m1s",
+        "dtr",
+        "rts",
+        "boot=1,wait 20,reset=1,wait 100,reset=0,wait 200,boot=0",
+        "boot=0,wait 20,reset=1,wait 100,reset=0",
+    ),
+    // ESP-style two transistor auto download circuit on the CH340 kits
+    (
+        "ai-thinker",
+        "rts",
+        "dtr",
+        "boot=0,reset=1,wait 100,boot=1,reset=0,wait 50,boot=0",
+        "boot=0,reset=1,wait 100,reset=0",
+    ),
+];
+
+impl Default for ResetConfig {
+    fn default() -> Self {
+        ResetConfig::preset("default").unwrap()
+    }
+}
+
+impl ResetConfig {
+    pub fn preset(name: &str) -> Result<Self, Error> {
+        let &(_, reset_pin, boot_pin, to_flash, to_run) = PRESETS
+            .iter()
+            .find(|preset| preset.0.eq_ignore_ascii_case(name))
+            .ok_or(Error::ArgsError)?;
+        Ok(ResetConfig {
+            reset_pin: reset_pin.parse()?,
+            boot_pin: boot_pin.parse()?,
+            to_flash: to_flash.parse()?,
+            to_run: to_run.parse()?,
+        })
+    }
+
+    /// The default sequences on other pins
+    pub fn with_pins(reset_pin: &str, boot_pin: &str) -> Result<Self, Error> {
+        Ok(ResetConfig {
+            reset_pin: reset_pin.parse()?,
+            boot_pin: boot_pin.parse()?,
+            ..ResetConfig::default()
+        })
+    }
+
+    pub fn run(&self, sequence: &Sequence, transport: &mut dyn Transport) -> Result<(), Error> {
+        for step in &sequence.0 {
+            match *step {
+                Step::Boot(level) => self.boot_pin.set(transport, level)?,
+                Step::Reset(level) => self.reset_pin.set(transport, level)?,
+                Step::Line(pin, level) => pin.set(transport, level)?,
+                Step::Wait(duration) => sleep(duration),
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,193 @@
+//! Recording of protocol frames, and a transport that replays them.
+//!
+//! A trace is a text file with one frame per line:
+//!
+//! ```text
+//!     0.512034 > BootInfoReq          10000000
+//!     0.514210 < BootInfoReq          4f4b14000100000000000000000000000000000000000000
+//! ```
+//!
+//! `>` lines are requests, `<` lines are the raw bytes read back for them.
+//! Pipelined requests are answered in order, so responses pair up with the
+//! oldest unanswered request.
+
+use crate::{
+    framing::{Framer, Incoming},
+    transport::Transport,
+    Error,
+};
+use serial::BaudRate;
+use std::{
+    collections::VecDeque,
+    fs::{read_to_string, File},
+    io::{self, LineWriter, Read, Write},
+    path::Path,
+    time::{Duration, Instant},
+};
+
+pub struct TraceRecorder {
+    writer: LineWriter<File>,
+    start: Instant,
+}
+
+impl TraceRecorder {
+    pub fn create(path: impl AsRef<Path>) -> Result<Self, Error> {
+        Ok(TraceRecorder {
+            writer: LineWriter::new(File::create(path)?),
+            start: Instant::now(),
+        })
+    }
+
+    fn record(&mut self, direction: char, name: &str, data: &[u8]) -> Result<(), Error> {
+        writeln!(
+            self.writer,
+            "{:>12.6} {} {:<20} {}",
+            self.start.elapsed().as_secs_f64(),
+            direction,
+            name,
+            hex::encode(data)
+        )?;
+        Ok(())
+    }
+
+    pub fn request(&mut self, name: &str, data: &[u8]) -> Result<(), Error> {
+        self.record('>', name, data)
+    }
+
+    pub fn response(&mut self, name: &str, data: &[u8]) -> Result<(), Error> {
+        self.record('<', name, data)
+    }
+}
+
+struct Exchange {
+    name: String,
+    request: Vec<u8>,
+    response: Vec<u8>,
+}
+
+/// Plays a recorded trace back as the device.
+///
+/// Handshakes are always acknowledged. Each request must match the command
+/// id of the next recorded one, which is answered with the recorded bytes.
+pub struct ReplayTransport {
+    exchanges: VecDeque<Exchange>,
+    framer: Framer,
+    output: VecDeque<u8>,
+    timeout: Duration,
+}
+
+impl ReplayTransport {
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, Error> {
+        Self::parse(&read_to_string(path)?)
+    }
+
+    pub fn parse(trace: &str) -> Result<Self, Error> {
+        let mut exchanges = VecDeque::new();
+        let mut answered = 0;
+        for line in trace.lines() {
+            let mut fields = line.split_whitespace();
+            let (direction, name) = match (fields.next(), fields.next(), fields.next()) {
+                (Some(_), Some(direction), Some(name)) => (direction, name),
+                _ => continue,
+            };
+            let data = hex::decode(fields.next().unwrap_or("")).map_err(|_| Error::ArgsError)?;
+            match direction {
+                ">" => exchanges.push_back(Exchange {
+                    name: name.to_string(),
+                    request: data,
+                    response: Vec::new(),
+                }),
+                "<" => {
+                    let exchange: &mut Exchange =
+                        exchanges.get_mut(answered).ok_or(Error::ArgsError)?;
+                    exchange.response = data;
+                    answered += 1;
+                }
+                _ => return Err(Error::ArgsError),
+            }
+        }
+        Ok(ReplayTransport {
+            exchanges,
+            framer: Framer::default(),
+            output: VecDeque::new(),
+            timeout: Duration::from_secs(1),
+        })
+    }
+
+    /// Recorded requests that haven't been replayed yet
+    pub fn remaining(&self) -> usize {
+        self.exchanges.len()
+    }
+}
+
+impl Read for ReplayTransport {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.output.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::TimedOut, "replay timeout"));
+        }
+        let size = buf.len().min(self.output.len());
+        for (dst, src) in buf.iter_mut().zip(self.output.drain(..size)) {
+            *dst = src;
+        }
+        Ok(size)
+    }
+}
+
+impl Write for ReplayTransport {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        for incoming in self.framer.push(buf) {
+            let frame = match incoming {
+                Incoming::Handshake => {
+                    self.output.extend(b"OK");
+                    continue;
+                }
+                Incoming::Frame(frame) => frame,
+            };
+            let exchange = self
+                .exchanges
+                .pop_front()
+                .ok_or_else(|| io::Error::new(io::ErrorKind::UnexpectedEof, "trace exhausted"))?;
+            if exchange.request.first() != frame.first() {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "trace diverged: expected {}, got command {:#04x}",
+                        exchange.name, frame[0]
+                    ),
+                ));
+            }
+            if exchange.request != frame {
+                log::warn!("Replayed {} with different payload", exchange.name);
+            }
+            self.output.extend(exchange.response);
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Transport for ReplayTransport {
+    fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    fn set_timeout(&mut self, timeout: Duration) -> Result<(), Error> {
+        self.timeout = timeout;
+        Ok(())
+    }
+
+    fn set_baud(&mut self, _speed: BaudRate) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn set_rts(&mut self, _level: bool) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn set_dtr(&mut self, _level: bool) -> Result<(), Error> {
+        Ok(())
+    }
+}
@@ -0,0 +1,140 @@
+//! Byte streams the flasher can talk to a chip over.
+
+use crate::Error;
+use serial::{BaudRate, SerialPort, SerialPortSettings};
+use std::{
+    io::{self, Read, Write},
+    net::{TcpStream, ToSocketAddrs},
+    time::Duration,
+};
+
+/// A byte stream with baud rate switching and reset/boot pin control
+pub trait Transport: Read + Write {
+    fn timeout(&self) -> Duration;
+    fn set_timeout(&mut self, timeout: Duration) -> Result<(), Error>;
+    fn set_baud(&mut self, speed: BaudRate) -> Result<(), Error>;
+    /// Whether `set_baud` changes the line rate, false where something else owns it
+    fn can_set_baud(&self) -> bool {
+        true
+    }
+    fn set_rts(&mut self, level: bool) -> Result<(), Error>;
+    fn set_dtr(&mut self, level: bool) -> Result<(), Error>;
+}
+
+impl<T: SerialPort> Transport for T {
+    fn timeout(&self) -> Duration {
+        SerialPort::timeout(self)
+    }
+
+    fn set_timeout(&mut self, timeout: Duration) -> Result<(), Error> {
+        Ok(SerialPort::set_timeout(self, timeout)?)
+    }
+
+    fn set_baud(&mut self, speed: BaudRate) -> Result<(), Error> {
+        Ok(self.reconfigure(&|setup: &mut dyn SerialPortSettings| setup.set_baud_rate(speed))?)
+    }
+
+    fn set_rts(&mut self, level: bool) -> Result<(), Error> {
+        Ok(SerialPort::set_rts(self, level)?)
+    }
+
+    fn set_dtr(&mut self, level: bool) -> Result<(), Error> {
+        Ok(SerialPort::set_dtr(self, level)?)
+    }
+}
+
+impl Transport for Box<dyn Transport> {
+    fn timeout(&self) -> Duration {
+        (**self).timeout()
+    }
+
+    fn set_timeout(&mut self, timeout: Duration) -> Result<(), Error> {
+        (**self).set_timeout(timeout)
+    }
+
+    fn set_baud(&mut self, speed: BaudRate) -> Result<(), Error> {
+        (**self).set_baud(speed)
+    }
+
+    fn can_set_baud(&self) -> bool {
+        (**self).can_set_baud()
+    }
+
+    fn set_rts(&mut self, level: bool) -> Result<(), Error> {
+        (**self).set_rts(level)
+    }
+
+    fn set_dtr(&mut self, level: bool) -> Result<(), Error> {
+        (**self).set_dtr(level)
+    }
+}
+
+/// Raw TCP connection to a serial port server such as ser2net.
+///
+/// The server owns the line settings and control pins, so baud rate changes
+/// and pin toggles are ignored. The eflash_loader autobauds on the handshake,
+/// so the whole session simply runs at the server's configured rate.
+pub struct TcpTransport {
+    stream: TcpStream,
+    timeout: Duration,
+}
+
+impl TcpTransport {
+    pub fn connect(addr: impl ToSocketAddrs) -> Result<Self, Error> {
+        let stream = TcpStream::connect(addr)?;
+        stream.set_nodelay(true)?;
+        let mut transport = TcpTransport {
+            stream,
+            timeout: Duration::from_secs(1),
+        };
+        transport.set_timeout(transport.timeout)?;
+        Ok(transport)
+    }
+}
+
+impl Read for TcpTransport {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.stream.read(buf)
+    }
+}
+
+impl Write for TcpTransport {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.stream.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+impl Transport for TcpTransport {
+    fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    fn set_timeout(&mut self, timeout: Duration) -> Result<(), Error> {
+        // a zero read timeout is rejected by the socket
+        self.stream
+            .set_read_timeout(Some(timeout.max(Duration::from_millis(1))))?;
+        self.timeout = timeout;
+        Ok(())
+    }
+
+    fn set_baud(&mut self, speed: BaudRate) -> Result<(), Error> {
+        log::debug!("Ignoring baud rate {} on TCP transport", speed.speed());
+        Ok(())
+    }
+
+    fn can_set_baud(&self) -> bool {
+        false
+    }
+
+    fn set_rts(&mut self, _level: bool) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn set_dtr(&mut self, _level: bool) -> Result<(), Error> {
+        Ok(())
+    }
+}
@@ -0,0 +1,30 @@
+//! Helpers shared by the integration tests
+#![allow(dead_code)]
+
+use blflash::{
+    chip::ChipType, emulator::Emulator, reset::ResetConfig, trace::TraceRecorder,
+    transport::Transport, Error, Flasher,
+};
+use serial::BaudRate;
+
+/// Connect over any transport at the speeds the CLI defaults to
+pub fn connect_with(
+    chip: impl Into<Option<ChipType>>,
+    transport: impl Transport + 'static,
+    reset: ResetConfig,
+    trace: Option<TraceRecorder>,
+) -> Result<Flasher, Error> {
+    Flasher::connect(
+        chip,
+        transport,
+        BaudRate::Baud115200,
+        BaudRate::from_speed(1000000),
+        reset,
+        trace,
+    )
+}
+
+/// Connect to an emulated chip
+pub fn connect(chip: ChipType, emulator: &Emulator) -> Flasher {
+    connect_with(chip, emulator.clone(), ResetConfig::default(), None).unwrap()
+}
@@ -0,0 +1,100 @@
+use blflash::{
+    detect::{choose, detect, SerialDevice},
+    Error,
+};
+use std::{
+    env, fs,
+    os::unix::fs::symlink,
+    path::{Path, PathBuf},
+    process,
+};
+
+/// tty name, its interface dir under devices, and the USB ids if it has any
+type FakeTty<'a> = (&'a str, &'a str, Option<(u16, u16)>);
+
+/// A sysfs tree with the given ttys, the ones without ids are platform UARTs
+fn fake_sysfs(name: &str, ttys: &[FakeTty]) -> PathBuf {
+    let root = env::temp_dir().join(format!("blflash-sysfs-{}-{}", name, process::id()));
+    let _ = fs::remove_dir_all(&root);
+    fs::create_dir_all(root.join("class/tty")).unwrap();
+    for &(tty, interface, ids) in ttys {
+        let interface = root.join("devices").join(interface);
+        fs::create_dir_all(&interface).unwrap();
+        if let Some((vid, pid)) = ids {
+            let usb_device = interface.parent().unwrap();
+            fs::write(usb_device.join("idVendor"), format!("{:04x}\n", vid)).unwrap();
+            fs::write(usb_device.join("idProduct"), format!("{:04x}\n", pid)).unwrap();
+        }
+        let class = root.join("class/tty").join(tty);
+        fs::create_dir_all(&class).unwrap();
+        symlink(&interface, class.join("device")).unwrap();
+    }
+    root
+}
+
+#[test]
+fn detects_known_bridges_only() {
+    let root = fake_sysfs(
+        "known",
+        &[
+            ("ttyUSB0", "usb1/1-1/1-1:1.0", Some((0x1a86, 0x7523))),
+            ("ttyACM0", "usb1/1-2/1-2:1.0", Some((0xffff, 0xffff))),
+            ("ttyUSB1", "usb1/1-3/1-3:1.0", Some((0x067b, 0x2303))),
+            ("ttyS0", "platform/serial8250/tty", None),
+        ],
+    );
+
+    let devices = detect(&root, Path::new("/dev")).unwrap();
+    fs::remove_dir_all(&root).unwrap();
+
+    assert_eq!(
+        devices,
+        vec![
+            SerialDevice {
+                path: PathBuf::from("/dev/ttyACM0"),
+                vid: 0xffff,
+                pid: 0xffff,
+                bridge: "Bouffalo CDC",
+            },
+            SerialDevice {
+                path: PathBuf::from("/dev/ttyUSB0"),
+                vid: 0x1a86,
+                pid: 0x7523,
+                bridge: "CH340",
+            },
+        ]
+    );
+}
+
+#[test]
+fn choose_picks_single_device_or_asks() {
+    let root = fake_sysfs(
+        "choose",
+        &[
+            ("ttyUSB0", "usb1/1-1/1-1:1.0", Some((0x10c4, 0xea60))),
+            ("ttyUSB1", "usb1/1-2/1-2:1.0", Some((0x0403, 0x6001))),
+        ],
+    );
+    let devices = detect(&root, Path::new("/dev")).unwrap();
+    fs::remove_dir_all(&root).unwrap();
+
+    let mut output = Vec::new();
+    let port = choose(&devices[..1], &b""[..], &mut output).unwrap();
+    assert_eq!(port, PathBuf::from("/dev/ttyUSB0"));
+    assert!(output.is_empty());
+
+    let port = choose(&devices, &b"2\n"[..], &mut output).unwrap();
+    assert_eq!(port, PathBuf::from("/dev/ttyUSB1"));
+    let listing = String::from_utf8(output).unwrap();
+    assert!(listing.contains("1: /dev/ttyUSB0 CP210x (10c4:ea60)"));
+    assert!(listing.contains("2: /dev/ttyUSB1 FT232R (0403:6001)"));
+
+    assert!(matches!(
+        choose(&devices, &b"3\n"[..], Vec::new()),
+        Err(Error::ArgsError)
+    ));
+    assert!(matches!(
+        choose(&[], &b""[..], Vec::new()),
+        Err(Error::NoSerialPort)
+    ));
+}
@@ -0,0 +1,77 @@
+mod common;
+
+use blflash::{
+    chip::{Bl602, Bl616, Bl702, Bl808, Chip, ChipType},
+    efuse::BL602_EFUSE,
+    emulator::Emulator,
+    Error, Flasher,
+};
+
+fn connect(emulator: &Emulator) -> Flasher {
+    common::connect(ChipType::BL602(Bl602), emulator)
+}
+
+#[test]
+fn program_and_decode_mac() {
+    let emulator = Emulator::new(ChipType::BL602(Bl602), 0x200000);
+    let mut flasher = connect(&emulator);
+
+    let mac = [0x18, 0xb9, 0x05, 0x88, 0x11, 0x22, 0x00, 0x00];
+    flasher.program_efuse(0x14, &mac, &[0xff; 8]).unwrap();
+
+    let efuse = emulator.efuse();
+    assert_eq!(&efuse[0x14..0x1c], &mac);
+    assert_eq!(
+        BL602_EFUSE.mac_address(&efuse, 0),
+        Some([0x18, 0xb9, 0x05, 0x88, 0x11, 0x22])
+    );
+    let lines = BL602_EFUSE.decode(&efuse, 0);
+    assert!(lines.contains(&format!("{:<20} 18:b9:05:88:11:22", "mac_address")));
+    assert!(lines.contains(&format!("{:<20} empty", "key_slot_0")));
+}
+
+#[test]
+fn program_with_mask_only_touches_masked_bits() {
+    let emulator = Emulator::new(ChipType::BL602(Bl602), 0x200000);
+    let mut flasher = connect(&emulator);
+
+    // ef_sboot_en = 0b11
+    flasher
+        .program_efuse(0x0, &[0xff, 0xff, 0xff, 0xff], &[0x30, 0, 0, 0])
+        .unwrap();
+
+    let efuse = emulator.efuse();
+    assert_eq!(&efuse[0..4], &[0x30, 0, 0, 0]);
+    let sboot_en = BL602_EFUSE.field("ef_sboot_en").unwrap();
+    assert_eq!(sboot_en.value(&efuse, 0), Some(3));
+    assert_eq!(
+        BL602_EFUSE
+            .field("ef_sf_aes_mode")
+            .unwrap()
+            .value(&efuse, 0),
+        Some(0)
+    );
+}
+
+#[test]
+fn program_refuses_to_clear_blown_bits() {
+    let emulator = Emulator::new(ChipType::BL602(Bl602), 0x200000);
+    let mut flasher = connect(&emulator);
+
+    flasher
+        .program_efuse(0x10, &[1, 0, 0, 0], &[0xff; 4])
+        .unwrap();
+    match flasher.program_efuse(0x10, &[2, 0, 0, 0], &[0xff; 4]) {
+        Err(Error::EfuseBlown(0x10)) => {}
+        r => panic!("unexpected {:?}", r),
+    }
+    assert_eq!(flasher.read_efuse(0x10, 4).unwrap(), vec![1, 0, 0, 0]);
+}
+
+#[test]
+fn only_known_efuse_maps_are_decoded() {
+    assert!(Bl602.efuse_layout().is_some());
+    assert!(Bl616.efuse_layout().is_none());
+    assert!(Bl702.efuse_layout().is_none());
+    assert!(Bl808.efuse_layout().is_none());
+}
@@ -0,0 +1,882 @@
+mod common;
+
+use blflash::{
+    chip::bl602::DEFAULT_BOOTHEADER_CFG,
+    chip::{Bl602, Bl616, Bl702, Bl808, Chip, ChipSelect, ChipType},
+    elf::{FirmwareImage, RomSegment},
+    emulator::{Emulator, Fault, Mode},
+    format::Format,
+    image::{BootHeader, BootHeaderCfgFile},
+    reset::ResetConfig,
+    Boot2Opt, Connection, Error, FlashInfo, FlashTiming,
+};
+use common::{connect, connect_with};
+use serial::BaudRate;
+use std::io::{Read, Write};
+use structopt::StructOpt;
+
+const FLASH_SIZE: usize = 0x200000;
+
+fn pattern(len: usize, seed: u8) -> Vec<u8> {
+    (0..len)
+        .map(|i| (i as u8).wrapping_mul(31) ^ seed)
+        .collect()
+}
+
+/// A minimal little-endian RISC-V ELF32 with one loadable segment per entry
+fn elf(entry: u32, segments: &[(u32, &[u8])]) -> Vec<u8> {
+    let mut elf = b"\x7fELF\x01\x01\x01".to_vec();
+    elf.resize(16, 0);
+    for half in [2u16, 0xf3] {
+        elf.extend(&half.to_le_bytes());
+    }
+    for word in [1u32, entry, 52, 0, 0] {
+        elf.extend(&word.to_le_bytes());
+    }
+    for half in [52u16, 32, segments.len() as u16, 40, 0, 0] {
+        elf.extend(&half.to_le_bytes());
+    }
+    let mut offset = 52 + 32 * segments.len() as u32;
+    for &(addr, data) in segments {
+        let size = data.len() as u32;
+        for word in [1u32, offset, addr, addr, size, size, 7, 4] {
+            elf.extend(&word.to_le_bytes());
+        }
+        offset += size;
+    }
+    for &(_, data) in segments {
+        elf.extend(data);
+    }
+    elf
+}
+
+#[test]
+fn connect_reads_boot_info() {
+    let emulator = Emulator::new(ChipType::BL602(Bl602), FLASH_SIZE);
+    let flasher = connect(ChipType::BL602(Bl602), &emulator);
+
+    assert_eq!(flasher.boot_info().bootrom_version, 1);
+    assert_eq!(flasher.boot_info().len, 20);
+    assert_eq!(emulator.mode(), Mode::BootRom);
+}
+
+#[test]
+fn connect_reads_bl616_and_bl808_boot_info() {
+    for chip in [ChipType::BL616(Bl616), ChipType::BL808(Bl808)] {
+        let emulator = Emulator::new(chip.clone(), FLASH_SIZE);
+        let mut flasher = connect(chip.clone(), &emulator);
+
+        assert_eq!(flasher.boot_info().len, 24);
+        assert!(matches!(
+            flasher.flash_info(),
+            Err(Error::MissingEflashLoader(name)) if name == chip.name()
+        ));
+    }
+}
+
+#[test]
+fn connect_detects_chip_from_boot_info() {
+    let auto = |chip: ChipType| {
+        connect_with(
+            None,
+            Emulator::new(chip, FLASH_SIZE),
+            ResetConfig::default(),
+            None,
+        )
+    };
+
+    // BL602 and BL70x can't be told apart, nor BL616 and BL808
+    for (chip, names) in [
+        (ChipType::BL602(Bl602), "BL602 or BL702"),
+        (ChipType::BL702(Bl702), "BL602 or BL702"),
+        (ChipType::BL616(Bl616), "BL616 or BL808"),
+        (ChipType::BL808(Bl808), "BL616 or BL808"),
+    ] {
+        match auto(chip) {
+            Err(Error::AmbiguousChip(candidates)) => assert_eq!(candidates, names),
+            r => panic!("unexpected {:?}", r.map(|f| f.chip())),
+        }
+    }
+}
+
+#[test]
+fn chip_defaults_to_bl602() {
+    let connection = Connection::from_iter(&["blflash"]);
+    assert!(matches!(
+        connection.chip,
+        ChipSelect::Chip(ChipType::BL602(_))
+    ));
+}
+
+#[test]
+fn chip_names_parse_or_fail() {
+    assert!(matches!("auto".parse(), Ok(ChipSelect::Auto)));
+    assert!(matches!(
+        "BL616".parse(),
+        Ok(ChipSelect::Chip(ChipType::BL616(_)))
+    ));
+    assert!(matches!(
+        "bl706".parse(),
+        Ok(ChipSelect::Chip(ChipType::BL702(_)))
+    ));
+    assert!(matches!(
+        "bl808".parse(),
+        Ok(ChipSelect::Chip(ChipType::BL808(_)))
+    ));
+    assert!(matches!(
+        "bl606".parse::<ChipSelect>(),
+        Err(Error::UnknownChip(name)) if name == "bl606"
+    ));
+}
+
+#[test]
+fn load_segments_programs_flash() {
+    let emulator = Emulator::new(ChipType::BL602(Bl602), FLASH_SIZE);
+    let mut flasher = connect(ChipType::BL602(Bl602), &emulator);
+
+    let first = pattern(10000, 0x5a);
+    let second = pattern(300, 0xa5);
+    let segments = vec![
+        RomSegment::from_slice(0x0, &first),
+        RomSegment::from_slice(0x10000, &second),
+    ];
+    flasher.load_segments(false, segments.into_iter()).unwrap();
+
+    let flash = emulator.flash();
+    assert_eq!(emulator.mode(), Mode::EflashLoader);
+    assert_eq!(emulator.baud_rate(), BaudRate::from_speed(1000000));
+    assert_eq!(&flash[..first.len()], &first[..]);
+    assert_eq!(&flash[0x10000..0x10000 + second.len()], &second[..]);
+    assert!(flash[0x10000 + second.len()..].iter().all(|&b| b == 0xff));
+}
+
+#[test]
+fn load_segments_overwrites_stale_data() {
+    let emulator = Emulator::with_flash(ChipType::BL602(Bl602), vec![0x00; FLASH_SIZE]);
+    let mut flasher = connect(ChipType::BL602(Bl602), &emulator);
+
+    let data = pattern(5000, 0x11);
+    let segments = vec![RomSegment::from_slice(0x2000, &data)];
+    flasher.load_segments(true, segments.into_iter()).unwrap();
+
+    assert_eq!(&emulator.flash()[0x2000..0x2000 + data.len()], &data[..]);
+}
+
+#[test]
+fn check_segments_against_flash() {
+    let data = pattern(4096, 0x33);
+    let mut flash = vec![0xff; FLASH_SIZE];
+    flash[0x1000..0x2000].copy_from_slice(&data);
+    let emulator = Emulator::with_flash(ChipType::BL602(Bl602), flash);
+    let mut flasher = connect(ChipType::BL602(Bl602), &emulator);
+
+    let segments = vec![RomSegment::from_slice(0x1000, &data)];
+    flasher.check_segments(segments.into_iter()).unwrap();
+}
+
+#[test]
+fn dump_flash_reads_range() {
+    let mut flash = pattern(FLASH_SIZE, 0x77);
+    flash[0] = 0x42;
+    let emulator = Emulator::with_flash(ChipType::BL602(Bl602), flash.clone());
+    let mut flasher = connect(ChipType::BL602(Bl602), &emulator);
+
+    let mut output = Vec::new();
+    flasher.dump_flash(0x0..0x3000, &mut output).unwrap();
+
+    assert_eq!(output, &flash[..0x3000]);
+}
+
+#[test]
+fn strict_checksum_accepts_flasher_frames() {
+    let emulator = Emulator::new(ChipType::BL602(Bl602), FLASH_SIZE);
+    emulator.set_strict_checksum(true);
+    let mut flasher = connect(ChipType::BL602(Bl602), &emulator);
+
+    let data = pattern(6000, 0x42);
+    let segments = vec![RomSegment::from_slice(0x4000, &data)];
+    flasher.load_segments(false, segments.into_iter()).unwrap();
+
+    assert_eq!(&emulator.flash()[0x4000..0x4000 + data.len()], &data[..]);
+}
+
+#[test]
+fn fatal_checksum_errors_fail_on_corrupted_request() {
+    for fatal in [false, true] {
+        let emulator = Emulator::new(ChipType::BL602(Bl602), FLASH_SIZE);
+        emulator.set_strict_checksum(true);
+        let mut flasher = connect(ChipType::BL602(Bl602), &emulator);
+        flasher.set_compress(false);
+        flasher.set_fatal_checksum_errors(fatal);
+        emulator.inject_fault(0x31, Fault::Corrupt);
+
+        let data = pattern(0x3000, 0x17);
+        let result = flasher.load_segments(
+            true,
+            vec![RomSegment::from_slice(0x8000, &data)].into_iter(),
+        );
+
+        if fatal {
+            assert!(matches!(result, Err(Error::ChecksumRejected)));
+        } else {
+            // the rejected chunk is sent again
+            result.unwrap();
+            assert_eq!(&emulator.flash()[0x8000..0xb000], &data[..]);
+        }
+    }
+}
+
+#[test]
+fn strict_checksum_rejects_corrupted_frame() {
+    let mut emulator = Emulator::new(ChipType::BL602(Bl602), FLASH_SIZE);
+    emulator.set_strict_checksum(true);
+
+    // BootInfoReq with a bogus checksum byte
+    emulator.write_all(&[0x10, 0x01, 0x00, 0x00]).unwrap();
+    let mut resp = [0u8; 4];
+    emulator.read_exact(&mut resp).unwrap();
+    assert_eq!(resp, [b'F', b'L', 0x03, 0x01]);
+
+    emulator.write_all(&[0x10, 0x00, 0x00, 0x00]).unwrap();
+    let mut resp = [0u8; 2];
+    emulator.read_exact(&mut resp).unwrap();
+    assert_eq!(&resp, b"OK");
+}
+
+#[test]
+fn load_segments_retries_failed_chunks() {
+    let emulator = Emulator::new(ChipType::BL602(Bl602), FLASH_SIZE);
+    let mut flasher = connect(ChipType::BL602(Bl602), &emulator);
+    flasher.set_compress(false);
+    emulator.inject_fault(0x31, Fault::Garbage);
+    emulator.inject_fault(0x31, Fault::Drop);
+
+    let data = pattern(20000, 0x24);
+    let segments = vec![RomSegment::from_slice(0x8000, &data)];
+    flasher.load_segments(false, segments.into_iter()).unwrap();
+
+    assert_eq!(&emulator.flash()[0x8000..0x8000 + data.len()], &data[..]);
+}
+
+#[test]
+fn load_segments_resumes_after_reset() {
+    let emulator = Emulator::new(ChipType::BL602(Bl602), FLASH_SIZE);
+    let mut flasher = connect(ChipType::BL602(Bl602), &emulator);
+    flasher.set_compress(false);
+    emulator.inject_fault(0x31, Fault::Drop);
+    emulator.inject_fault(0x31, Fault::Reset);
+
+    let data = pattern(20000, 0x81);
+    let segments = vec![RomSegment::from_slice(0x8000, &data)];
+    flasher.load_segments(false, segments.into_iter()).unwrap();
+
+    assert_eq!(emulator.mode(), Mode::EflashLoader);
+    assert_eq!(&emulator.flash()[0x8000..0x8000 + data.len()], &data[..]);
+}
+
+#[test]
+fn load_segments_gives_up_on_dead_link() {
+    let emulator = Emulator::new(ChipType::BL602(Bl602), FLASH_SIZE);
+    let mut flasher = connect(ChipType::BL602(Bl602), &emulator);
+    flasher.set_compress(false);
+    for _ in 0..20 {
+        emulator.inject_fault(0x31, Fault::Drop);
+    }
+
+    let data = pattern(4000, 0x99);
+    let segments = vec![RomSegment::from_slice(0x0, &data)];
+    assert!(flasher.load_segments(true, segments.into_iter()).is_err());
+}
+
+#[test]
+fn flash_info_reports_size() {
+    let emulator = Emulator::new(ChipType::BL602(Bl602), 0x400000);
+    let mut flasher = connect(ChipType::BL602(Bl602), &emulator);
+
+    let info = flasher.flash_info().unwrap();
+    assert_eq!(info.jedec_id, [0xef, 0x40, 0x16]);
+    assert_eq!(info.size, 0x400000);
+    assert_eq!(info.manufacturer(), "Winbond");
+}
+
+#[test]
+fn load_segments_refuses_segment_past_flash_end() {
+    let emulator = Emulator::new(ChipType::BL602(Bl602), 0x100000);
+    let mut flasher = connect(ChipType::BL602(Bl602), &emulator);
+
+    let data = pattern(0x2000, 0x10);
+    let segments = vec![
+        RomSegment::from_slice(0x0, &data),
+        RomSegment::from_slice(0xff000, &data),
+    ];
+    match flasher.load_segments(false, segments.into_iter()) {
+        Err(Error::SegmentExceedsFlash {
+            addr: 0xff000,
+            size: 0x2000,
+            flash_size: 0x100000,
+        }) => {}
+        r => panic!("unexpected {:?}", r.err()),
+    }
+    // nothing was written
+    assert!(emulator.flash().iter().all(|&b| b == 0xff));
+}
+
+#[test]
+fn unknown_flash_size_skips_bounds_check() {
+    let emulator = Emulator::new(ChipType::BL602(Bl602), 0x100000);
+    emulator.set_jedec_id([0xef, 0x40, 0x00]);
+    let mut flasher = connect(ChipType::BL602(Bl602), &emulator);
+
+    let data = pattern(0x2000, 0x10);
+    flasher
+        .load_segments(
+            false,
+            vec![RomSegment::from_slice(0x4000, &data)].into_iter(),
+        )
+        .unwrap();
+    assert_eq!(&emulator.flash()[0x4000..0x6000], &data[..]);
+
+    assert_eq!(flasher.erase_flash(0x4000..0x5000).unwrap(), 0x4000..0x5000);
+    assert!(emulator.flash()[0x4000..0x5000].iter().all(|&b| b == 0xff));
+}
+
+#[test]
+fn unsupported_flash_id() {
+    assert!(matches!(
+        FlashInfo::from_jedec_id([0xef, 0x40, 0x00]),
+        Err(Error::UnsupportedFlash(0x00))
+    ));
+    assert_eq!(
+        FlashInfo::from_jedec_id([0xc8, 0x40, 0x15]).unwrap().size,
+        0x200000
+    );
+}
+
+#[test]
+fn erase_flash_aligns_to_sectors() {
+    let emulator = Emulator::with_flash(ChipType::BL602(Bl602), vec![0x00; FLASH_SIZE]);
+    let mut flasher = connect(ChipType::BL602(Bl602), &emulator);
+
+    let erased = flasher.erase_flash(0x1e9100..0x1ea001).unwrap();
+    assert_eq!(erased, 0x1e9000..0x1eb000);
+
+    let flash = emulator.flash();
+    assert!(flash[0x1e9000..0x1eb000].iter().all(|&b| b == 0xff));
+    assert_eq!(flash[0x1e8fff], 0x00);
+    assert_eq!(flash[0x1eb000], 0x00);
+}
+
+#[test]
+fn erase_flash_rejects_range_past_address_space() {
+    let emulator = Emulator::new(ChipType::BL602(Bl602), FLASH_SIZE);
+    let mut flasher = connect(ChipType::BL602(Bl602), &emulator);
+
+    assert!(matches!(
+        flasher.erase_flash(0xffff_f000..0xffff_f001),
+        Err(Error::RangeOverflow {
+            addr: 0xffff_f000,
+            size: 1
+        })
+    ));
+}
+
+#[test]
+fn erase_chip_clears_everything() {
+    let emulator = Emulator::with_flash(ChipType::BL602(Bl602), vec![0x00; FLASH_SIZE]);
+    let mut flasher = connect(ChipType::BL602(Bl602), &emulator);
+
+    flasher.erase_chip().unwrap();
+    assert!(emulator.flash().iter().all(|&b| b == 0xff));
+}
+
+#[test]
+fn memory_peek_and_poke() {
+    let emulator = Emulator::new(ChipType::BL602(Bl602), FLASH_SIZE);
+    emulator.set_word(0x40000000, 0x12345678);
+    let mut flasher = connect(ChipType::BL602(Bl602), &emulator);
+
+    flasher.write_word(0x40000004, 0xdeadbeef).unwrap();
+    assert_eq!(emulator.word(0x40000004), 0xdeadbeef);
+
+    let data = flasher.read_memory(0x40000000, 0x808).unwrap();
+    assert_eq!(data.len(), 0x808);
+    assert_eq!(
+        &data[0..8],
+        &[0x78, 0x56, 0x34, 0x12, 0xef, 0xbe, 0xad, 0xde]
+    );
+    assert!(data[8..].iter().all(|&b| b == 0));
+
+    assert!(matches!(
+        flasher.read_memory(0x40000001, 4),
+        Err(Error::ArgsError)
+    ));
+    // past the end of the address space
+    assert!(matches!(
+        flasher.read_memory(0xfffffffc, 8),
+        Err(Error::ArgsError)
+    ));
+}
+
+#[test]
+fn run_ram_image_loads_every_segment() {
+    let emulator = Emulator::new(ChipType::BL602(Bl602), FLASH_SIZE);
+    let mut flasher = connect(ChipType::BL602(Bl602), &emulator);
+
+    let code = [0x13, 0x00, 0x00, 0x00, 0x6f, 0x00, 0x00, 0x00];
+    let data = [0xaa, 0xbb, 0xcc];
+    let elf = elf(0x22010000, &[(0x22010000, &code), (0x42020000, &data)]);
+    let image = FirmwareImage::from_data(&elf).unwrap();
+    let segments = image.to_ram_segments(&Bl602).unwrap();
+    assert_eq!(segments.len(), 2);
+
+    let BootHeaderCfgFile {
+        mut boot_header_cfg,
+    } = toml::from_slice(DEFAULT_BOOTHEADER_CFG).unwrap();
+    let ram_image = boot_header_cfg
+        .make_ram_image(image.entry(), &segments)
+        .unwrap();
+    flasher.run_ram_image(&ram_image).unwrap();
+
+    assert_eq!(emulator.word(0x22010000), 0x00000013);
+    assert_eq!(emulator.word(0x22010004), 0x0000006f);
+    assert_eq!(emulator.word(0x42020000), 0x00ccbbaa);
+    assert!(emulator.flash().iter().all(|&b| b == 0xff));
+}
+
+#[test]
+fn run_ram_image_loads_bl616_segments() {
+    let emulator = Emulator::new(ChipType::BL616(Bl616), FLASH_SIZE);
+    let mut flasher = connect(ChipType::BL616(Bl616), &emulator);
+
+    let code = [0x13, 0x00, 0x00, 0x00, 0x6f, 0x00, 0x00, 0x00];
+    let elf = elf(0x62fc1000, &[(0x62fc1000, &code)]);
+    let image = FirmwareImage::from_data(&elf).unwrap();
+    let segments = image.to_ram_segments(&Bl616).unwrap();
+
+    let mut boot_header_cfg = Bl616
+        .parse_boot_header_cfg(Bl616.get_default_boot_header_cfg())
+        .unwrap();
+    let ram_image = boot_header_cfg
+        .make_ram_image(image.entry(), &segments)
+        .unwrap();
+    assert_eq!(ram_image.len(), 256 + 16 + code.len());
+    flasher.run_ram_image(&ram_image).unwrap();
+
+    assert_eq!(emulator.word(0x62fc1000), 0x00000013);
+    assert_eq!(emulator.word(0x62fc1004), 0x0000006f);
+}
+
+#[test]
+fn bl616_loads_eflash_loader_given_to_it() {
+    let emulator = Emulator::new(ChipType::BL616(Bl616), FLASH_SIZE);
+    let mut flasher = connect(ChipType::BL616(Bl616), &emulator);
+    assert!(matches!(
+        flasher.flash_info(),
+        Err(Error::MissingEflashLoader("BL616"))
+    ));
+
+    let code = [0x6f, 0x00, 0x00, 0x00];
+    let elf = elf(0x62fc1000, &[(0x62fc1000, &code)]);
+    let image = FirmwareImage::from_data(&elf).unwrap();
+    let segments = image.to_ram_segments(&Bl616).unwrap();
+    let mut boot_header_cfg = Bl616
+        .parse_boot_header_cfg(Bl616.get_default_boot_header_cfg())
+        .unwrap();
+    let loader = boot_header_cfg
+        .make_ram_image(image.entry(), &segments)
+        .unwrap();
+    flasher.set_eflash_loader(loader);
+
+    assert_eq!(flasher.flash_info().unwrap().size, FLASH_SIZE as u32);
+    assert_eq!(emulator.mode(), Mode::EflashLoader);
+    assert_eq!(emulator.word(0x62fc1000), 0x0000006f);
+}
+
+#[test]
+fn bl616_boot2_has_to_be_given() {
+    let boot = Boot2Opt::from_iter(&["blflash"]);
+    assert!(matches!(
+        boot.with_boot2(&Bl616, &[0; 64]),
+        Err(Error::MissingBoot2)
+    ));
+
+    let path = std::env::temp_dir().join(format!("blflash-boot2-{}.bin", std::process::id()));
+    std::fs::write(&path, [0x55; 16]).unwrap();
+    let boot = Boot2Opt::from_iter(&["blflash", "--boot2", path.to_str().unwrap()]);
+    let segments = boot.with_boot2(&Bl616, &[0; 64]).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(segments[0].addr, 0x0);
+    assert_eq!(&segments[0].data[0x2000..0x2010], &[0x55; 16]);
+}
+
+#[test]
+fn bl702_loads_ram_images_and_lays_out_boot2() {
+    let emulator = Emulator::new(ChipType::BL702(Bl702), FLASH_SIZE);
+    let mut flasher = connect(ChipType::BL702(Bl702), &emulator);
+    assert_eq!(flasher.chip().name(), "BL702");
+    assert!(matches!(
+        flasher.flash_info(),
+        Err(Error::MissingEflashLoader("BL702"))
+    ));
+
+    let code = [0x13, 0x00, 0x00, 0x00];
+    let elf = elf(0x22014000, &[(0x22014000, &code)]);
+    let image = FirmwareImage::from_data(&elf).unwrap();
+    let segments = image.to_ram_segments(&Bl702).unwrap();
+    let mut boot_header_cfg = Bl702
+        .parse_boot_header_cfg(Bl702.get_default_boot_header_cfg())
+        .unwrap();
+    let ram_image = boot_header_cfg
+        .make_ram_image(image.entry(), &segments)
+        .unwrap();
+    flasher.run_ram_image(&ram_image).unwrap();
+    assert_eq!(emulator.word(0x22014000), 0x00000013);
+
+    let partition_cfg = toml::from_slice(Bl702.get_default_partition_cfg()).unwrap();
+    let boot_header_cfg = Bl702
+        .parse_boot_header_cfg(Bl702.get_default_boot_header_cfg())
+        .unwrap();
+    let segments = Bl702
+        .with_boot2(
+            partition_cfg,
+            boot_header_cfg,
+            Vec::new(),
+            &[0u8; 16],
+            &[0u8; 64],
+        )
+        .unwrap();
+    let addrs: Vec<_> = segments.iter().map(|segment| segment.addr).collect();
+    assert_eq!(addrs, [0x0, 0xe000, 0xf000, 0x10000]);
+}
+
+#[test]
+fn bl616_boot_header_layout() {
+    let mut boot_header_cfg = Bl616
+        .parse_boot_header_cfg(Bl616.get_default_boot_header_cfg())
+        .unwrap();
+    let image = boot_header_cfg.make_image(0x2000, vec![0x55; 100]).unwrap();
+    let header = &image[..256];
+    let word = |offset: usize| {
+        let bytes = &header[offset..offset + 4];
+        u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+    };
+
+    assert_eq!(image.len(), 0x2000 + 112);
+    assert_eq!(&header[0..4], b"BFNP");
+    assert_eq!(&header[8..12], b"FCFG");
+    assert_eq!(&header[100..104], b"PCFG");
+    // no_segment, crc_ignore and hash_ignore
+    assert_eq!(word(120) & 0x30100, 0x30100);
+    assert_eq!(word(124), 0x2000);
+    assert_eq!(word(132), 112);
+    assert_eq!(word(176), 0xa0000000);
+    assert_eq!(word(252), crc::crc32::checksum_ieee(&header[..252]));
+    assert!(image[256..0x2000].iter().all(|&b| b == 0xff));
+}
+
+#[test]
+fn bl616_flash_segments_come_from_the_xip_window() {
+    let elf = elf(
+        0xa0000000,
+        &[(0xa0000000, &[1, 2, 3, 4]), (0xa0001000, &[5])],
+    );
+    let image = FirmwareImage::from_data(&elf).unwrap();
+
+    let bin = image.to_flash_bin(&Bl616);
+    assert_eq!(bin.len(), 0x1001);
+    assert_eq!(&bin[..4], &[1, 2, 3, 4]);
+    assert_eq!(bin[0x1000], 5);
+    assert!(image.to_flash_bin(&Bl602).is_empty());
+}
+
+#[test]
+fn bl808_places_core_images_behind_group_headers() {
+    let m0 = elf(0x58000000, &[(0x58000000, &[0x11; 100])]);
+    let m0 = FirmwareImage::from_data(&m0).unwrap().to_flash_bin(&Bl808);
+    let partition_cfg = toml::from_slice(Bl808.get_default_partition_cfg()).unwrap();
+    let segments = Bl808
+        .with_cores(
+            partition_cfg,
+            Bl808.get_default_boot_header_cfg(),
+            vec![("m0", m0), ("d0", vec![0x22; 40])],
+        )
+        .unwrap();
+    let addrs: Vec<_> = segments.iter().map(|segment| segment.addr).collect();
+    assert_eq!(addrs, [0x0, 0x1000, 0xe000, 0xf000, 0x10000, 0x120000]);
+
+    let mut flash = vec![0xff; FLASH_SIZE];
+    for segment in &segments {
+        let addr = segment.addr as usize;
+        flash[addr..addr + segment.data.len()].copy_from_slice(&segment.data);
+    }
+    let word = |offset: usize| {
+        let bytes = &flash[offset..offset + 4];
+        u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+    };
+    // group 0 boots M0 from FW
+    assert_eq!(&flash[0..4], b"BFNP");
+    assert_eq!(word(128) >> 11 & 0xf, 0);
+    assert_eq!(word(132), 0x10000);
+    assert_eq!(word(140), 112);
+    assert_eq!((flash[176], word(180), word(184)), (1, 0x10000, 0x58000000));
+    assert_eq!(flash[192], 0);
+    assert_eq!(word(348), crc::crc32::checksum_ieee(&flash[..348]));
+    // group 1 boots D0 from D0FW, offsets are from its header
+    assert_eq!(word(0x1000 + 128) >> 11 & 0xf, 1);
+    assert_eq!(word(0x1000 + 132), 0x11f000);
+    assert_eq!(word(0x1000 + 140), 48);
+    assert_eq!((flash[0x1000 + 176], flash[0x1000 + 192]), (0, 1));
+    assert_eq!(word(0x1000 + 196), 0x11f000);
+    assert_eq!(&flash[0x10000..0x10064], &[0x11; 100][..]);
+    assert_eq!(&flash[0x120000..0x120028], &[0x22; 40][..]);
+}
+
+#[test]
+fn core_images_need_a_core_and_room() {
+    let partition_cfg = || toml::from_slice(Bl808.get_default_partition_cfg()).unwrap();
+    let cfg = Bl808.get_default_boot_header_cfg();
+
+    assert!(matches!(
+        Bl602.with_cores(partition_cfg(), cfg, vec![("m0", vec![0; 16])]),
+        Err(Error::UnsupportedCore(core)) if core == "m0"
+    ));
+    assert!(matches!(
+        Bl808.with_cores(partition_cfg(), cfg, vec![("c906", vec![0; 16])]),
+        Err(Error::UnsupportedCore(core)) if core == "c906"
+    ));
+    assert!(matches!(
+        Bl808.with_cores(partition_cfg(), cfg, vec![("lp", vec![0; 0x20001])]),
+        Err(Error::PartitionOverflow { name, .. }) if name == "LPFW"
+    ));
+}
+
+#[test]
+fn sparse_elf_flashes_only_its_regions() {
+    let elf = elf(
+        0x23000000,
+        &[
+            (0x23000000, &[1, 2, 3, 4]),
+            (0x23000004, &[5, 6]),
+            (0x23100000, &[7]),
+        ],
+    );
+    let image = FirmwareImage::from_data(&elf).unwrap();
+    assert_eq!(image.to_flash_bin(&Bl602).len(), 0x100001);
+
+    let segments = blflash::read_segments(&Bl602, Format::Elf, &elf).unwrap();
+    let regions: Vec<_> = segments
+        .iter()
+        .map(|segment| (segment.addr, &segment.data[..]))
+        .collect();
+    assert_eq!(
+        regions,
+        [(0x0, &[1, 2, 3, 4, 5, 6][..]), (0x100000, &[7][..])]
+    );
+
+    let emulator = Emulator::with_flash(ChipType::BL602(Bl602), vec![0x00; FLASH_SIZE]);
+    let mut flasher = connect(ChipType::BL602(Bl602), &emulator);
+    flasher.load_segments(false, segments.into_iter()).unwrap();
+    let flash = emulator.flash();
+    assert_eq!(&flash[..6], &[1, 2, 3, 4, 5, 6]);
+    assert_eq!(flash[0x100000], 7);
+    // erased by the sector, everything past the written sectors stays
+    assert!(flash[0x1000..0x100000].iter().all(|&b| b == 0x00));
+
+    let bin = [0xaa; 8];
+    let segments = blflash::read_segments(&Bl602, Format::Bin, &bin).unwrap();
+    assert_eq!((segments.len(), segments[0].addr), (1, 0));
+}
+
+#[test]
+fn flash_linked_elf_is_not_ram_loadable() {
+    let elf = elf(0x23000000, &[(0x23000000, &[0u8; 16])]);
+    let image = FirmwareImage::from_data(&elf).unwrap();
+
+    assert!(matches!(
+        image.to_ram_segments(&Bl602),
+        Err(Error::ElfNotRamLoadable)
+    ));
+}
+
+#[test]
+fn eflash_loader_falls_back_to_slower_baud() {
+    let emulator = Emulator::new(ChipType::BL602(Bl602), FLASH_SIZE);
+    emulator.set_max_baud(921_600);
+    let mut flasher = connect(ChipType::BL602(Bl602), &emulator);
+
+    flasher.flash_info().unwrap();
+    assert_eq!(flasher.baud_rate().unwrap().speed(), 921_600);
+    assert_eq!(emulator.baud_rate().speed(), 921_600);
+}
+
+#[test]
+fn load_segments_lowers_baud_after_corruption() {
+    let emulator = Emulator::new(ChipType::BL602(Bl602), FLASH_SIZE);
+    let mut flasher = connect(ChipType::BL602(Bl602), &emulator);
+    flasher.set_compress(false);
+    flasher.set_pipeline(false);
+    for _ in 0..3 {
+        emulator.inject_fault(0x31, Fault::Garbage);
+    }
+
+    let data = pattern(0x3000, 0x33);
+    flasher
+        .load_segments(
+            true,
+            vec![RomSegment::from_slice(0x2000, &data)].into_iter(),
+        )
+        .unwrap();
+
+    assert_eq!(flasher.baud_rate().unwrap().speed(), 921_600);
+    assert_eq!(&emulator.flash()[0x2000..0x5000], &data[..]);
+}
+
+#[test]
+fn load_segments_compresses_repetitive_data() {
+    let emulator = Emulator::new(ChipType::BL602(Bl602), FLASH_SIZE);
+    let mut flasher = connect(ChipType::BL602(Bl602), &emulator);
+    // a retried chunk is a retransmit, a reset restarts the stream
+    emulator.inject_fault(0x3f, Fault::Garbage);
+    emulator.inject_fault(0x3f, Fault::Reset);
+
+    let mut data = vec![0u8; 0x20000];
+    data[0x1000..0x1100].copy_from_slice(&pattern(0x100, 0x44));
+    flasher
+        .load_segments(
+            true,
+            vec![RomSegment::from_slice(0x10000, &data)].into_iter(),
+        )
+        .unwrap();
+
+    assert_eq!(&emulator.flash()[0x10000..0x30000], &data[..]);
+}
+
+#[test]
+fn load_segments_without_compression() {
+    let emulator = Emulator::new(ChipType::BL602(Bl602), FLASH_SIZE);
+    let mut flasher = connect(ChipType::BL602(Bl602), &emulator);
+    flasher.set_compress(false);
+    // uncompressed writes never use the decompress command
+    emulator.inject_fault(0x3f, Fault::Drop);
+
+    let data = vec![0u8; 0x8000];
+    flasher
+        .load_segments(
+            true,
+            vec![RomSegment::from_slice(0x10000, &data)].into_iter(),
+        )
+        .unwrap();
+
+    assert_eq!(&emulator.flash()[0x10000..0x18000], &data[..]);
+}
+
+#[test]
+fn pipelined_program_falls_back_to_lock_step() {
+    let emulator = Emulator::new(ChipType::BL602(Bl602), FLASH_SIZE);
+    let mut flasher = connect(ChipType::BL602(Bl602), &emulator);
+    flasher.set_compress(false);
+    // the responses after a lost one look like they belong to earlier requests
+    emulator.inject_fault(0x31, Fault::Garbage);
+    emulator.inject_fault(0x31, Fault::Drop);
+
+    let data = pattern(0x6000, 0x55);
+    flasher
+        .load_segments(
+            true,
+            vec![RomSegment::from_slice(0x20000, &data)].into_iter(),
+        )
+        .unwrap();
+
+    assert_eq!(&emulator.flash()[0x20000..0x26000], &data[..]);
+}
+
+#[test]
+fn pipelined_dump_falls_back_to_lock_step() {
+    let flash = pattern(FLASH_SIZE, 0x66);
+    let emulator = Emulator::with_flash(ChipType::BL602(Bl602), flash.clone());
+    let mut flasher = connect(ChipType::BL602(Bl602), &emulator);
+    flasher.flash_info().unwrap();
+    for _ in 0..3 {
+        emulator.inject_fault(0x32, Fault::Garbage);
+    }
+    emulator.inject_fault(0x32, Fault::Drop);
+
+    let mut output = Vec::new();
+    flasher.dump_flash(0x1000..0x9800, &mut output).unwrap();
+
+    assert_eq!(output, &flash[0x1000..0x9800]);
+}
+
+#[test]
+fn pipelined_program_recovers_from_lost_reply() {
+    let emulator = Emulator::new(ChipType::BL602(Bl602), FLASH_SIZE);
+    let mut flasher = connect(ChipType::BL602(Bl602), &emulator);
+    flasher.set_compress(false);
+    // every acknowledgement after the lost one is taken for the chunk before it
+    emulator.inject_fault(0x31, Fault::Drop);
+
+    let data = pattern(0x10000, 0x5a);
+    flasher
+        .load_segments(
+            true,
+            vec![RomSegment::from_slice(0x20000, &data)].into_iter(),
+        )
+        .unwrap();
+
+    assert_eq!(&emulator.flash()[0x20000..0x30000], &data[..]);
+}
+
+#[test]
+fn pipelined_dump_rereads_after_lost_reply() {
+    // blocks that differ from each other, so a shifted one shows
+    let flash: Vec<u8> = (0..FLASH_SIZE).map(|i| (i >> 12) as u8 ^ i as u8).collect();
+    let emulator = Emulator::with_flash(ChipType::BL602(Bl602), flash.clone());
+    let mut flasher = connect(ChipType::BL602(Bl602), &emulator);
+    emulator.inject_fault(0x32, Fault::Drop);
+
+    let mut output = Vec::new();
+    flasher.dump_flash(0..0x40000, &mut output).unwrap();
+
+    assert_eq!(output, &flash[0..0x40000]);
+}
+
+#[test]
+fn erase_waits_through_pending_replies() {
+    let emulator = Emulator::with_flash(ChipType::BL602(Bl602), vec![0; FLASH_SIZE]);
+    let mut flasher = connect(ChipType::BL602(Bl602), &emulator);
+    emulator.set_pending(0x30, 20);
+    emulator.set_pending(0x3c, 200);
+
+    flasher.erase_flash(0x1000..0x3000).unwrap();
+    assert!(emulator.flash()[0x1000..0x3000].iter().all(|&b| b == 0xff));
+
+    flasher.erase_chip().unwrap();
+    assert!(emulator.flash().iter().all(|&b| b == 0xff));
+}
+
+#[test]
+fn short_commands_tolerate_pending_replies() {
+    let flash = pattern(FLASH_SIZE, 0x77);
+    let emulator = Emulator::with_flash(ChipType::BL602(Bl602), flash.clone());
+    let mut flasher = connect(ChipType::BL602(Bl602), &emulator);
+    emulator.set_pending(0x32, 2);
+
+    let mut output = Vec::new();
+    flasher.dump_flash(0x0..0x2000, &mut output).unwrap();
+
+    assert_eq!(output, &flash[0..0x2000]);
+}
+
+#[test]
+fn flash_timing_scales_with_operation_size() {
+    let timing = FlashTiming::default();
+
+    // a sector on each side of a 64K block
+    assert_eq!(
+        timing.erase(0xf000..0x21000),
+        timing.sector_erase * 2 + timing.blk64k_erase
+    );
+    assert_eq!(timing.erase(0x8000..0x10000), timing.blk32k_erase);
+    assert_eq!(timing.erase(0x1800..0x1900), timing.sector_erase);
+    assert_eq!(timing.program(4000), timing.page_program * 16);
+}
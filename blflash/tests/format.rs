@@ -0,0 +1,188 @@
+use blflash::{
+    chip::Bl602,
+    elf::RomSegment,
+    format::{self, Format, UF2_FAMILY_BL602},
+    Error,
+};
+use std::path::Path;
+
+fn ihex(kind: u8, addr: u16, data: &[u8]) -> String {
+    let mut record = vec![data.len() as u8, (addr >> 8) as u8, addr as u8, kind];
+    record.extend(data);
+    let sum = record.iter().fold(0u8, |sum, &b| sum.wrapping_add(b));
+    record.push(sum.wrapping_neg());
+    format!(":{}\n", hex::encode_upper(record))
+}
+
+fn srec(kind: char, addr: u32, addr_len: usize, data: &[u8]) -> String {
+    let mut record = vec![(addr_len + data.len() + 1) as u8];
+    record.extend(&addr.to_be_bytes()[4 - addr_len..]);
+    record.extend(data);
+    let sum = record.iter().fold(0u8, |sum, &b| sum.wrapping_add(b));
+    record.push(!sum);
+    format!("S{}{}\n", kind, hex::encode_upper(record))
+}
+
+fn uf2_block(flags: u32, addr: u32, family: u32, data: &[u8]) -> Vec<u8> {
+    let mut block = Vec::new();
+    for word in [
+        0x0A324655,
+        0x9E5D5157,
+        flags,
+        addr,
+        data.len() as u32,
+        0,
+        1,
+        family,
+    ] {
+        block.extend(&u32::to_le_bytes(word));
+    }
+    block.extend(data);
+    block.resize(508, 0);
+    block.extend(&u32::to_le_bytes(0x0AB16F30));
+    block
+}
+
+fn regions<'a>(segments: &'a [RomSegment]) -> Vec<(u32, &'a [u8])> {
+    segments
+        .iter()
+        .map(|segment| (segment.addr, &segment.data[..]))
+        .collect()
+}
+
+#[test]
+fn format_from_extension_or_content() {
+    let detect = |path: &str, data: &[u8]| Format::detect(Some(Path::new(path)), data);
+
+    assert_eq!(detect("fw.HEX", b""), Format::IntelHex);
+    assert_eq!(detect("fw.s37", b""), Format::Srec);
+    assert_eq!(detect("fw.uf2", b""), Format::Uf2);
+    assert_eq!(detect("fw.bin", b":00000001FF"), Format::Bin);
+    assert_eq!(detect("fw", b"\x7fELF\x01"), Format::Elf);
+    assert_eq!(detect("fw", b":00000001FF"), Format::IntelHex);
+    assert_eq!(detect("fw", b"S00600004844521B"), Format::Srec);
+    assert_eq!(detect("fw", &uf2_block(0, 0, 0, &[])), Format::Uf2);
+    assert_eq!(Format::detect(None, &[0x13, 0, 0, 0]), Format::Bin);
+}
+
+#[test]
+fn intel_hex_joins_records_at_flash_offsets() {
+    let hex = [
+        ihex(0x04, 0, &[0x23, 0x00]),
+        ihex(0x00, 0x0000, &[1, 2, 3, 4]),
+        ihex(0x00, 0x0004, &[5, 6]),
+        ihex(0x04, 0, &[0x00, 0x10]),
+        ihex(0x00, 0x0000, &[7]),
+        ihex(0x05, 0, &[0x23, 0, 0, 0]),
+        ihex(0x01, 0, &[]),
+    ]
+    .concat();
+
+    let segments = format::read_segments(&Bl602, Format::IntelHex, hex.as_bytes()).unwrap();
+    assert_eq!(
+        regions(&segments),
+        [(0x0, &[1, 2, 3, 4, 5, 6][..]), (0x100000, &[7][..])]
+    );
+
+    let bin = blflash::read_image(&Bl602, Format::IntelHex, hex.as_bytes()).unwrap();
+    assert_eq!(bin.len(), 0x100001);
+    assert!(bin[6..0x100000].iter().all(|&b| b == 0xff));
+}
+
+#[test]
+fn intel_hex_rejects_bad_checksum() {
+    let mut bad = ihex(0x00, 0x0010, &[1, 2]);
+    bad.replace_range(9..11, "FF");
+    let hex = [ihex(0x00, 0, &[0]), bad].concat();
+
+    assert!(matches!(
+        format::parse_ihex(hex.as_bytes()),
+        Err(Error::InvalidRecord { line: 2, .. })
+    ));
+
+    let mut garbled = ihex(0x00, 0, &[0]).into_bytes();
+    garbled.extend(b"\xff\xfe\n");
+    garbled.extend(ihex(0x01, 0, &[]).into_bytes());
+    assert!(matches!(
+        format::parse_ihex(&garbled),
+        Err(Error::InvalidRecord { line: 2, .. })
+    ));
+}
+
+#[test]
+fn addresses_outside_flash_are_rejected() {
+    // RAM on the data bus, neither in the XIP window nor a flash offset
+    let hex = [ihex(0x04, 0, &[0x42, 0x00]), ihex(0x00, 0x8000, &[1])].concat();
+    assert!(matches!(
+        format::read_segments(&Bl602, Format::IntelHex, hex.as_bytes()),
+        Err(Error::SegmentExceedsFlash {
+            addr: 0x42008000,
+            ..
+        })
+    ));
+
+    // running off the end of the XIP window
+    let end = srec('3', 0x23fffffe, 4, &[1, 2, 3]);
+    assert!(matches!(
+        format::read_segments(&Bl602, Format::Srec, end.as_bytes()),
+        Err(Error::SegmentExceedsFlash { addr: 0xfffffe, .. })
+    ));
+}
+
+#[test]
+fn srec_reads_every_address_size() {
+    let file = [
+        srec('0', 0, 2, b"HDR"),
+        srec('1', 0x1000, 2, &[1, 2]),
+        srec('2', 0x020000, 3, &[3]),
+        srec('3', 0x23000010, 4, &[4, 5]),
+        srec('5', 3, 2, &[]),
+        srec('7', 0x23000000, 4, &[]),
+    ]
+    .concat();
+
+    let segments = format::read_segments(&Bl602, Format::Srec, file.as_bytes()).unwrap();
+    assert_eq!(
+        regions(&segments),
+        [
+            (0x10, &[4, 5][..]),
+            (0x1000, &[1, 2][..]),
+            (0x20000, &[3][..])
+        ]
+    );
+
+    let mut bad = srec('1', 0, 2, &[1]);
+    bad.replace_range(8..10, "00");
+    assert!(matches!(
+        format::parse_srec(bad.as_bytes()),
+        Err(Error::InvalidRecord { line: 1, .. })
+    ));
+}
+
+#[test]
+fn uf2_keeps_main_flash_blocks_of_our_family() {
+    let uf2 = [
+        uf2_block(0, 0x23000000, 0, &[1, 2]),
+        uf2_block(0x2000, 0x23000002, UF2_FAMILY_BL602, &[3]),
+        uf2_block(0x2000, 0x23000100, 0xe48bff56, &[9]),
+        uf2_block(0x1, 0x23000200, 0, &[9]),
+    ]
+    .concat();
+
+    let segments = format::read_segments(&Bl602, Format::Uf2, &uf2).unwrap();
+    assert_eq!(regions(&segments), [(0x0, &[1, 2, 3][..])]);
+
+    // chips without a registered family only take untagged blocks
+    let segments = format::parse_uf2(&uf2, None).unwrap();
+    assert_eq!(regions(&segments), [(0x23000000, &[1, 2][..])]);
+
+    let other = uf2_block(0x2000, 0x23000000, 0xe48bff56, &[9]);
+    assert!(matches!(
+        format::read_segments(&Bl602, Format::Uf2, &other),
+        Err(Error::EmptyImage)
+    ));
+    assert!(matches!(
+        format::parse_uf2(&uf2[..600], Some(UF2_FAMILY_BL602)),
+        Err(Error::InvalidRecord { line: 2, .. })
+    ));
+}
@@ -0,0 +1,93 @@
+mod common;
+
+use blflash::{
+    chip::{Bl602, ChipType},
+    elf::RomSegment,
+    emulator::Emulator,
+    multi::{expand_ports, run_parallel, summarize},
+    reset::ResetConfig,
+    Error,
+};
+use common::connect_with;
+use std::{env, fs, process};
+
+fn flash(emulator: Emulator, bar: &indicatif::ProgressBar, data: &[u8]) -> Result<(), Error> {
+    let mut flasher = connect_with(
+        ChipType::BL602(Bl602),
+        emulator,
+        ResetConfig::default(),
+        None,
+    )?;
+    flasher.set_progress_bar(bar.clone());
+    flasher.load_segments(
+        false,
+        vec![RomSegment::from_slice(0x100000, data)].into_iter(),
+    )
+}
+
+#[test]
+fn run_parallel_flashes_every_device_and_reports_failures() {
+    let data: Vec<u8> = (0..20000u32).map(|i| (i * 7) as u8).collect();
+    let emulators = [
+        Emulator::new(ChipType::BL602(Bl602), 0x200000),
+        // too small for the segment
+        Emulator::new(ChipType::BL602(Bl602), 0x80000),
+        Emulator::new(ChipType::BL602(Bl602), 0x200000),
+    ];
+    let devices = emulators
+        .iter()
+        .enumerate()
+        .map(|(i, emulator)| (format!("emu{}", i), emulator.clone()))
+        .collect();
+
+    let results = run_parallel(devices, |emulator, bar| flash(emulator, bar, &data));
+
+    let names: Vec<_> = results.iter().map(|r| r.name.as_str()).collect();
+    assert_eq!(names, ["emu0", "emu1", "emu2"]);
+    assert!(results[0].result.is_ok());
+    assert!(matches!(
+        results[1].result,
+        Err(Error::SegmentExceedsFlash { .. })
+    ));
+    assert!(results[2].result.is_ok());
+    for i in [0, 2] {
+        assert_eq!(
+            &emulators[i].flash()[0x100000..0x100000 + data.len()],
+            &data[..]
+        );
+    }
+    assert!(matches!(
+        summarize(&results),
+        Err(Error::DevicesFailed {
+            failed: 1,
+            total: 3
+        })
+    ));
+}
+
+#[test]
+fn expand_ports_matches_globs_and_keeps_plain_ports() {
+    let dir = env::temp_dir().join(format!("blflash-ports-{}", process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    for name in ["ttyUSB1", "ttyUSB0", "ttyACM0"] {
+        fs::write(dir.join(name), b"").unwrap();
+    }
+    let usb = |name: &str| dir.join(name).to_string_lossy().into_owned();
+
+    let ports = expand_ports(&[
+        format!("{}/ttyUSB*", dir.display()),
+        usb("ttyUSB0"),
+        "tcp://localhost:2000".to_string(),
+    ])
+    .unwrap();
+
+    assert_eq!(
+        ports,
+        [
+            usb("ttyUSB0"),
+            usb("ttyUSB1"),
+            "tcp://localhost:2000".to_string()
+        ]
+    );
+    fs::remove_dir_all(&dir).unwrap();
+}
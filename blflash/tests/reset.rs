@@ -0,0 +1,73 @@
+mod common;
+
+use blflash::{
+    chip::{Bl602, ChipType},
+    emulator::Emulator,
+    reset::{Line, Pin, ResetConfig, Sequence, Step, PRESETS},
+};
+use common::connect_with;
+use std::time::Duration;
+
+#[test]
+fn sequence_parses_steps() {
+    let sequence: Sequence = "boot=1, reset=1,wait 50,!rts=0,wait=100,boot=0"
+        .parse()
+        .unwrap();
+
+    assert_eq!(
+        sequence.0,
+        vec![
+            Step::Boot(true),
+            Step::Reset(true),
+            Step::Wait(Duration::from_millis(50)),
+            Step::Line(
+                Pin {
+                    line: Line::Rts,
+                    inverted: true
+                },
+                false
+            ),
+            Step::Wait(Duration::from_millis(100)),
+            Step::Boot(false),
+        ]
+    );
+}
+
+#[test]
+fn sequence_rejects_unknown_steps() {
+    assert!("boot=2".parse::<Sequence>().is_err());
+    assert!("cts=1".parse::<Sequence>().is_err());
+    assert!("wait".parse::<Sequence>().is_err());
+    assert!("sleep 10".parse::<Sequence>().is_err());
+}
+
+#[test]
+fn every_preset_parses() {
+    for &(name, ..) in PRESETS {
+        ResetConfig::preset(name).unwrap();
+    }
+    assert!(ResetConfig::preset("Sipeed-M0").is_ok());
+    assert!(ResetConfig::preset("esp32").is_err());
+}
+
+#[test]
+fn connect_runs_flash_sequence() {
+    let emulator = Emulator::new(ChipType::BL602(Bl602), 0x200000);
+    let mut reset = ResetConfig::with_pins("dtr", "!rts").unwrap();
+    reset.to_flash = "boot=1,reset=1,reset=0,boot=0".parse().unwrap();
+    reset.to_run = "reset=1,reset=0".parse().unwrap();
+    let mut flasher = connect_with(ChipType::BL602(Bl602), emulator.clone(), reset, None).unwrap();
+    flasher.reset().unwrap();
+
+    assert_eq!(
+        emulator.line_changes(),
+        vec![
+            (Line::Rts, false),
+            (Line::Dtr, true),
+            (Line::Dtr, false),
+            (Line::Rts, true),
+            (Line::Dtr, true),
+            (Line::Dtr, false),
+        ]
+    );
+}
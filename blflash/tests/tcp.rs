@@ -0,0 +1,69 @@
+mod common;
+
+use blflash::{
+    chip::{Bl602, ChipType},
+    elf::RomSegment,
+    emulator::Emulator,
+    reset::ResetConfig,
+    transport::TcpTransport,
+};
+use common::connect_with;
+use serial::BaudRate;
+use std::{
+    io::{ErrorKind, Read, Write},
+    net::TcpListener,
+    thread,
+    time::Duration,
+};
+
+/// Serve `emulator` on a local port like ser2net in raw mode
+fn serve(emulator: Emulator) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_millis(1)))
+            .unwrap();
+        let mut device = emulator;
+        let mut buf = [0u8; 8192];
+        loop {
+            match stream.read(&mut buf) {
+                Ok(0) => return,
+                Ok(size) => device.write_all(&buf[..size]).unwrap(),
+                Err(e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => {}
+                Err(_) => return,
+            }
+            device.flush().unwrap();
+            while let Ok(size) = device.read(&mut buf) {
+                stream.write_all(&buf[..size]).unwrap();
+            }
+        }
+    });
+    addr
+}
+
+#[test]
+fn flash_over_tcp() {
+    let emulator = Emulator::new(ChipType::BL602(Bl602), 0x200000);
+    let addr = serve(emulator.clone());
+
+    let transport = TcpTransport::connect(addr).unwrap();
+    let mut flasher = connect_with(
+        ChipType::BL602(Bl602),
+        transport,
+        ResetConfig::default(),
+        None,
+    )
+    .unwrap();
+    assert_eq!(flasher.boot_info().bootrom_version, 1);
+
+    let data: Vec<u8> = (0..9000).map(|i| (i % 251) as u8).collect();
+    let segments = vec![RomSegment::from_slice(0x3000, &data)];
+    flasher.load_segments(false, segments.into_iter()).unwrap();
+
+    assert_eq!(&emulator.flash()[0x3000..0x3000 + data.len()], &data[..]);
+    // line settings belong to the server
+    assert_eq!(emulator.baud_rate(), BaudRate::Baud115200);
+    assert_eq!(flasher.baud_rate(), None);
+}
@@ -0,0 +1,82 @@
+mod common;
+
+use blflash::{
+    chip::{Bl602, ChipType},
+    emulator::Emulator,
+    reset::ResetConfig,
+    trace::{ReplayTransport, TraceRecorder},
+    transport::Transport,
+    Flasher,
+};
+use common::connect_with;
+use std::{env, fs, path::PathBuf, process};
+
+fn trace_path(name: &str) -> PathBuf {
+    env::temp_dir().join(format!("blflash-{}-{}.trace", name, process::id()))
+}
+
+fn connect(transport: impl Transport + 'static, trace: Option<TraceRecorder>) -> Flasher {
+    connect_with(
+        ChipType::BL602(Bl602),
+        transport,
+        ResetConfig::default(),
+        trace,
+    )
+    .unwrap()
+}
+
+fn record_dump(path: &PathBuf) -> Vec<u8> {
+    let flash: Vec<u8> = (0..0x200000).map(|i| (i % 253) as u8).collect();
+    let emulator = Emulator::with_flash(ChipType::BL602(Bl602), flash);
+    let mut flasher = connect(emulator, Some(TraceRecorder::create(path).unwrap()));
+    let mut output = Vec::new();
+    flasher.dump_flash(0x1000..0x3000, &mut output).unwrap();
+    output
+}
+
+#[test]
+fn trace_records_decoded_frames() {
+    let path = trace_path("record");
+    record_dump(&path);
+
+    let trace = fs::read_to_string(&path).unwrap();
+    fs::remove_file(&path).unwrap();
+    let lines: Vec<&str> = trace.lines().collect();
+    assert!(lines[0].contains("> BootInfoReq"));
+    assert!(lines[1].contains("< BootInfoReq"));
+    // OK, 20 byte payload
+    assert!(lines[1].contains(" 4f4b1400"));
+    assert_eq!(
+        lines
+            .iter()
+            .filter(|line| line.contains("> FlashRead"))
+            .count(),
+        2
+    );
+}
+
+#[test]
+fn replay_reproduces_session() {
+    let path = trace_path("replay");
+    let recorded = record_dump(&path);
+
+    let replay = ReplayTransport::open(&path).unwrap();
+    fs::remove_file(&path).unwrap();
+    let mut flasher = connect(replay, None);
+    let mut output = Vec::new();
+    flasher.dump_flash(0x1000..0x3000, &mut output).unwrap();
+
+    assert_eq!(output, recorded);
+}
+
+#[test]
+fn replay_detects_divergence() {
+    let path = trace_path("diverge");
+    record_dump(&path);
+
+    let replay = ReplayTransport::open(&path).unwrap();
+    fs::remove_file(&path).unwrap();
+    let mut flasher = connect(replay, None);
+    let segments = vec![blflash::elf::RomSegment::from_vec(0x0, vec![0; 16])];
+    assert!(flasher.load_segments(true, segments.into_iter()).is_err());
+}
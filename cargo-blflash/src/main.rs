@@ -17,6 +17,9 @@ struct BlflashOpt {
     /// Don't skip if hash matches
     #[structopt(short, long)]
     force: bool,
+    /// Send segments uncompressed
+    #[structopt(long)]
+    no_compress: bool,
     #[structopt(flatten)]
     boot: Boot2Opt,
     #[structopt(long)]
@@ -46,8 +49,12 @@ fn blflash_main(args: BlflashOpt) -> Result<()> {
 
     let flash_opt = FlashOpt {
         conn: args.conn,
-        image: path,
+        image: Some(path),
+        m0: None,
+        d0: None,
+        lp: None,
         force: args.force,
+        no_compress: args.no_compress,
         boot: args.boot,
     };
 
@@ -99,20 +106,14 @@ fn build(
         args.push("--release".to_string());
     }
 
-    match example {
-        Some(example) => {
-            args.push("--example".to_string());
-            args.push(example.to_string());
-        }
-        None => {}
+    if let Some(example) = example {
+        args.push("--example".to_string());
+        args.push(example.to_string());
     }
 
-    match features {
-        Some(features) => {
-            args.push("--features".to_string());
-            args.push(features.to_string());
-        }
-        None => {}
+    if let Some(features) = features {
+        args.push("--features".to_string());
+        args.push(features.to_string());
     }
 
     let mut command = Command::new("cargo");